@@ -0,0 +1,505 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::env::{env_find, env_get, param_arity, Env};
+use crate::eval::quasiquote;
+use crate::reader::{elem_span, form_span};
+use crate::symbol::{self, Symbol};
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Set, Sym, Vector};
+use crate::types::{hash_map, hash_set, MalArgs, MalErr, MalRet, MalVal, Span};
+
+// The analysis pass: a form is converted once into a tree of Code, with
+// macros expanded, special forms picked out and every local variable
+// resolved to the frame it lives in and its slot there.  eval.rs runs
+// the result, so a function body is only walked as a form when the fn*
+// is evaluated, not on every call.
+//
+// Each let*, each call of a fn* and each catch* gets a frame of slots at
+// run time, matching the frames pushed here while analyzing it.  Symbols
+// not bound in any of them are looked up by name in the env the analysis
+// started in.  Macros are expanded when the form is analyzed, so they
+// must be defined before a form using them is evaluated (or the fn*
+// containing it, for a function body).
+
+pub enum Code {
+    Const(MalVal),
+    // how many frames out, and the slot in that frame
+    Local(usize, usize),
+    // how many frames out the env the analysis started in is, the Sym,
+    // and where it was read from
+    Global(usize, MalVal, Option<Span>),
+    Def(Target, Rc<Code>),
+    DefMacro(Target, Rc<Code>),
+    // frame size, the slots to fill in order, and the body
+    Let(usize, Vec<(usize, Rc<Code>)>, Rc<Code>),
+    Do(Vec<Rc<Code>>, Rc<Code>),
+    If(Rc<Code>, Rc<Code>, Rc<Code>),
+    // with the params and body as written, for printing
+    Fn(Rc<Lambda>, Rc<MalVal>, Rc<MalVal>),
+    // the catch* body runs in a frame of the given size, with the
+    // exception in the first slot
+    Try(Rc<Code>, Option<(usize, Rc<Code>)>),
+    LazySeq(Rc<Code>),
+    Eval(Rc<Code>),
+    Macroexpand(MalVal),
+    Call(Call),
+    Vector(Vec<Rc<Code>>),
+    Hash(Vec<Rc<Code>>),
+    Set(Vec<Rc<Code>>),
+}
+
+pub enum Target {
+    Local(usize),
+    Global(MalVal),
+}
+
+pub struct Call {
+    pub f: Rc<Code>,
+    pub args: Vec<Rc<Code>>,
+    // for arity errors
+    pub name: Symbol,
+    pub span: Option<Span>,
+}
+
+// a fn* body, and how to lay out the frame it runs in: the params come
+// first, then a slot for each def! in the body
+pub struct Lambda {
+    // what def!, defmacro! or let* binds it to, else fn*, for arity errors
+    pub name: Symbol,
+    pub arity: (usize, Option<usize>),
+    pub frame_size: usize,
+    pub body: Rc<Code>,
+}
+
+impl fmt::Debug for Lambda {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Lambda({:?}, {})", self.arity, self.frame_size)
+    }
+}
+
+struct Frame {
+    // whether each name has been bound yet; the flags def! sets have no name
+    names: Vec<(Option<Symbol>, bool)>,
+    // the slots of names only def! binds, each with the slot of its flag
+    defs: Vec<(usize, usize)>,
+    is_fn: bool,
+}
+
+impl Frame {
+    fn new(names: Vec<(Option<Symbol>, bool)>, is_fn: bool) -> Frame {
+        Frame {
+            names,
+            defs: vec![],
+            is_fn,
+        }
+    }
+
+    fn position(&self, name: Symbol) -> Option<usize> {
+        self.names.iter().rposition(|&(n, _)| n == Some(name))
+    }
+
+    fn slot(&mut self, name: Symbol) -> usize {
+        match self.position(name) {
+            Some(i) => i,
+            None => {
+                self.names.push((Some(name), false));
+                self.names.len() - 1
+            }
+        }
+    }
+
+    // The slot for a def! of name, and the slot of its flag if the frame
+    // does not bind name otherwise.  Until the def! has run, reading name
+    // finds whatever an outer frame or the env has under it.
+    fn def_slot(&mut self, name: Symbol) -> (usize, Option<usize>) {
+        match self.position(name) {
+            Some(i) => (i, self.flag(i)),
+            None => {
+                let slot = self.slot(name);
+                self.names.push((None, true));
+                let flag = self.names.len() - 1;
+                self.defs.push((slot, flag));
+                (slot, Some(flag))
+            }
+        }
+    }
+
+    fn flag(&self, slot: usize) -> Option<usize> {
+        self.defs.iter().find(|&&(s, _)| s == slot).map(|&(_, f)| f)
+    }
+
+    fn bound(&mut self, slot: usize) {
+        self.names[slot].1 = true;
+    }
+}
+
+struct Analyzer<'a> {
+    env: &'a Env,
+    frames: Vec<Frame>,
+}
+
+pub fn analyze(form: &MalVal, env: &Env) -> Result<Rc<Code>, MalErr> {
+    Analyzer {
+        env,
+        frames: vec![],
+    }
+    .analyze(form, None)
+}
+
+fn err<T>(msg: &str) -> Result<T, MalErr> {
+    Err(ErrString(msg.to_string()))
+}
+
+// the number of args each special form takes; any other list is a call
+fn check_form(name: Symbol, n: usize) -> Result<(), MalErr> {
+    let (min, max) = match name {
+        symbol::DEF | symbol::DEFMACRO | symbol::LET | symbol::FN => (2, 2),
+        symbol::QUOTE
+        | symbol::QUASIQUOTE
+        | symbol::QUASIQUOTEEXPAND
+        | symbol::MACROEXPAND
+        | symbol::EVAL => (1, 1),
+        symbol::TRY => (1, 2),
+        symbol::IF => (1, 3),
+        _ => return Ok(()),
+    };
+    if n < min {
+        err(&format!("Too few arguments to {}", name))
+    } else if n > max {
+        err(&format!("Too many arguments to {}", name))
+    } else {
+        Ok(())
+    }
+}
+
+// a def! of a name with a flag slot sets the flag too, then gives the value
+fn set_flag(def: Code, flag: Option<usize>) -> Code {
+    match (flag, &def) {
+        (Some(flag), Code::Def(Target::Local(slot), _))
+        | (Some(flag), Code::DefMacro(Target::Local(slot), _)) => {
+            let slot = *slot;
+            let set = Code::Def(Target::Local(flag), Rc::new(Code::Const(Bool(true))));
+            Code::Do(
+                vec![Rc::new(def), Rc::new(set)],
+                Rc::new(Code::Local(0, slot)),
+            )
+        }
+        _ => def,
+    }
+}
+
+impl<'a> Analyzer<'a> {
+    // span is where the form was read from, when it is an element of a
+    // list that knows (symbols don't record their own position)
+    fn analyze(&mut self, form: &MalVal, span: Option<Span>) -> Result<Rc<Code>, MalErr> {
+        let code = match form {
+            Sym(s) => self.resolve(*s, form, span),
+            List(l, _) if !l.is_empty() => {
+                return self.analyze_list(form).map_err(|e| e.at(form_span(form)));
+            }
+            Vector(v, _) => self.analyze_coll(form, v.iter(), Code::Vector, |v| Ok(vector!(v)))?,
+            Hash(hm, _) => {
+                let kvs: Vec<&MalVal> = hm.iter().flat_map(|(k, v)| vec![k, v]).collect();
+                self.analyze_coll(form, kvs.into_iter(), Code::Hash, hash_map)?
+            }
+            Set(s, _) => self.analyze_coll(form, s.iter(), Code::Set, |s| Ok(hash_set(s)))?,
+            _ => Code::Const(form.clone()),
+        };
+        Ok(Rc::new(code))
+    }
+
+    // a literal whose elements are all constants is a constant itself,
+    // built from their values ('a is a, not (quote a))
+    fn analyze_coll<'b, I>(
+        &mut self,
+        form: &MalVal,
+        items: I,
+        make: fn(Vec<Rc<Code>>) -> Code,
+        build: fn(MalArgs) -> MalRet,
+    ) -> Result<Code, MalErr>
+    where
+        I: Iterator<Item = &'b MalVal>,
+    {
+        let mut codes = vec![];
+        for (i, x) in items.enumerate() {
+            codes.push(self.analyze(x, elem_span(form, i))?);
+        }
+        let mut vals = vec![];
+        for c in &codes {
+            match **c {
+                Code::Const(ref v) => vals.push(v.clone()),
+                _ => return Ok(make(codes)),
+            }
+        }
+        Ok(Code::Const(build(vals)?))
+    }
+
+    fn analyze_seq(&mut self, form: &MalVal, from: usize) -> Result<Vec<Rc<Code>>, MalErr> {
+        let mut codes = vec![];
+        if let List(l, _) = form {
+            for (i, x) in l.iter().enumerate().skip(from) {
+                codes.push(self.analyze(x, elem_span(form, i))?);
+            }
+        }
+        Ok(codes)
+    }
+
+    // A binding that is still being initialized can only be seen from
+    // inside a fn* (which runs later); until then its name refers to
+    // whatever it shadows, as in (let* [x (+ x 1)] ...).
+    fn resolve(&self, name: Symbol, form: &MalVal, span: Option<Span>) -> Code {
+        let mut crossed_fn = false;
+        let mut defs = vec![];
+        let mut code = None;
+        for (depth, frame) in self.frames.iter().rev().enumerate() {
+            if let Some(i) = frame.position(name) {
+                if frame.names[i].1 || crossed_fn {
+                    match frame.flag(i) {
+                        Some(flag) => defs.push((depth, i, flag)),
+                        None => {
+                            code = Some(Code::Local(depth, i));
+                            break;
+                        }
+                    }
+                }
+            }
+            crossed_fn |= frame.is_fn;
+        }
+        let code = code.unwrap_or_else(|| Code::Global(self.frames.len(), form.clone(), span));
+        // a def! that may not have run falls back to the binding outside it
+        defs.into_iter()
+            .rev()
+            .fold(code, |outer, (depth, slot, flag)| {
+                Code::If(
+                    Rc::new(Code::Local(depth, flag)),
+                    Rc::new(Code::Local(depth, slot)),
+                    Rc::new(outer),
+                )
+            })
+    }
+
+    fn is_local(&self, name: Symbol) -> bool {
+        self.frames.iter().any(|f| f.position(name).is_some())
+    }
+
+    // the macro a list form calls, if any
+    fn macro_call(&self, form: &MalVal) -> Option<MalVal> {
+        match form {
+            List(l, _) => match l[0] {
+                Sym(s) if !self.is_local(s) => {
+                    let e = env_find(self.env, s)?;
+                    match env_get(&e, &l[0]) {
+                        Ok(f @ MalFunc { is_macro: true, .. }) => Some(f),
+                        _ => None,
+                    }
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    fn analyze_list(&mut self, form: &MalVal) -> Result<Rc<Code>, MalErr> {
+        if let Some(mac) = self.macro_call(form) {
+            let args = match form {
+                List(l, _) => l.rest().to_vec(),
+                _ => vec![],
+            };
+            let expanded = mac.apply(args)?;
+            return self.analyze(&expanded, form_span(form));
+        }
+        let l = match form {
+            List(l, _) => l,
+            _ => return err("expected a list"),
+        };
+        if let Sym(s) = l[0] {
+            check_form(s, l.len() - 1)?;
+        }
+        let arg = |i: usize| l.iter().nth(i).cloned().unwrap_or(Nil);
+        let code = match l[0] {
+            Sym(symbol::DEF) => {
+                let (target, flag) = self.target(&arg(1))?;
+                let val = self.analyze_named(&arg(1), &arg(2), elem_span(form, 2))?;
+                self.bound(&target);
+                set_flag(Code::Def(target, val), flag)
+            }
+            Sym(symbol::DEFMACRO) => {
+                let (target, flag) = self.target(&arg(1))?;
+                let val = self.analyze_named(&arg(1), &arg(2), elem_span(form, 2))?;
+                self.bound(&target);
+                set_flag(Code::DefMacro(target, val), flag)
+            }
+            Sym(symbol::LET) => {
+                let binds: Vec<MalVal> = match arg(1).seq_iter() {
+                    Some(b) => b.cloned().collect(),
+                    None => return err("let* with non-List bindings"),
+                };
+                self.analyze_let(&binds, &arg(2))?
+            }
+            Sym(symbol::QUOTE) => Code::Const(arg(1)),
+            Sym(symbol::QUASIQUOTEEXPAND) => Code::Const(quasiquote(&arg(1))),
+            Sym(symbol::QUASIQUOTE) => return self.analyze(&quasiquote(&arg(1)), None),
+            Sym(symbol::MACROEXPAND) => Code::Macroexpand(arg(1)),
+            Sym(symbol::TRY) => {
+                let body = self.analyze(&arg(1), elem_span(form, 1))?;
+                let handler = match arg(2) {
+                    Nil if l.len() == 2 => None,
+                    List(c, _) if c.len() == 3 && matches!(c[0], Sym(symbol::CATCH)) => {
+                        match c[1] {
+                            Sym(e) => {
+                                let frame = Frame::new(vec![(Some(e), true)], false);
+                                Some(self.in_frame(frame, |a| a.analyze(&c[2], None))?)
+                            }
+                            _ => return err("invalid catch block"),
+                        }
+                    }
+                    _ => return err("invalid catch block"),
+                };
+                Code::Try(body, handler)
+            }
+            Sym(symbol::DO) => {
+                let mut codes = self.analyze_seq(form, 1)?;
+                match codes.pop() {
+                    Some(last) => Code::Do(codes, last),
+                    None => Code::Const(Nil),
+                }
+            }
+            Sym(symbol::IF) => Code::If(
+                self.analyze(&arg(1), elem_span(form, 1))?,
+                self.analyze(&arg(2), elem_span(form, 2))?,
+                self.analyze(&arg(3), elem_span(form, 3))?,
+            ),
+            Sym(symbol::LAZY_SEQ) => {
+                let mut codes = self.analyze_seq(form, 1)?;
+                match codes.pop() {
+                    Some(last) => Code::LazySeq(Rc::new(Code::Do(codes, last))),
+                    None => Code::LazySeq(Rc::new(Code::Const(Nil))),
+                }
+            }
+            Sym(symbol::FN) => {
+                let (params, body) = (arg(1), arg(2));
+                let lambda = self.analyze_fn(symbol::FN, &params, &body)?;
+                Code::Fn(Rc::new(lambda), Rc::new(params), Rc::new(body))
+            }
+            Sym(symbol::EVAL) => Code::Eval(self.analyze(&arg(1), elem_span(form, 1))?),
+            _ => {
+                let mut codes = self.analyze_seq(form, 0)?;
+                let f = codes.remove(0);
+                Code::Call(Call {
+                    f,
+                    args: codes,
+                    name: match l[0] {
+                        Sym(s) => s,
+                        _ => symbol::FN,
+                    },
+                    span: form_span(form),
+                })
+            }
+        };
+        Ok(Rc::new(code))
+    }
+
+    // where a def! or defmacro! stores its value: the innermost frame,
+    // or the env itself at the top level
+    // where a def! of name stores, and the slot of the flag it sets
+    fn target(&mut self, name: &MalVal) -> Result<(Target, Option<usize>), MalErr> {
+        match (name, self.frames.last_mut()) {
+            (Sym(s), Some(frame)) => {
+                let (slot, flag) = frame.def_slot(*s);
+                Ok((Target::Local(slot), flag))
+            }
+            (Sym(_), None) => Ok((Target::Global(name.clone()), None)),
+            _ => err("Env.set called with non-Str"),
+        }
+    }
+
+    fn bound(&mut self, target: &Target) {
+        if let (Target::Local(slot), Some(frame)) = (target, self.frames.last_mut()) {
+            frame.bound(*slot);
+        }
+    }
+
+    // runs f with frame pushed, returning the frame's final size too
+    fn in_frame<T, F>(&mut self, frame: Frame, f: F) -> Result<(usize, T), MalErr>
+    where
+        F: FnOnce(&mut Self) -> Result<T, MalErr>,
+    {
+        self.frames.push(frame);
+        let res = f(self);
+        let frame = self.frames.pop().unwrap();
+        Ok((frame.names.len(), res?))
+    }
+
+    fn analyze_let(&mut self, binds: &[MalVal], body: &MalVal) -> Result<Code, MalErr> {
+        if binds.len() % 2 != 0 {
+            return err("let* with an odd number of binding forms");
+        }
+        let mut frame = Frame::new(vec![], false);
+        for b in binds.iter().step_by(2) {
+            match b {
+                Sym(s) => frame.slot(*s),
+                _ => return err("let* with non-Sym binding"),
+            };
+        }
+        let (size, (inits, body)) = self.in_frame(frame, |a| {
+            let mut inits = vec![];
+            for pair in binds.chunks(2) {
+                let slot = match pair[0] {
+                    Sym(s) => a.frames.last_mut().unwrap().slot(s),
+                    _ => return err("let* with non-Sym binding"),
+                };
+                let init = a.analyze_named(&pair[0], pair.get(1).unwrap_or(&Nil), None)?;
+                a.frames.last_mut().unwrap().bound(slot);
+                inits.push((slot, init));
+            }
+            Ok((inits, a.analyze(body, None)?))
+        })?;
+        Ok(Code::Let(size, inits, body))
+    }
+
+    // the value bound to name, a fn* form there getting name as its own
+    fn analyze_named(
+        &mut self,
+        name: &MalVal,
+        form: &MalVal,
+        span: Option<Span>,
+    ) -> Result<Rc<Code>, MalErr> {
+        match (name, form) {
+            (Sym(s), List(l, _)) if l.len() == 3 && matches!(l[0], Sym(symbol::FN)) => {
+                let lambda = self
+                    .analyze_fn(*s, &l[1], &l[2])
+                    .map_err(|e| e.at(form_span(form)))?;
+                let (params, body) = (Rc::new(l[1].clone()), Rc::new(l[2].clone()));
+                Ok(Rc::new(Code::Fn(Rc::new(lambda), params, body)))
+            }
+            _ => self.analyze(form, span),
+        }
+    }
+
+    fn analyze_fn(
+        &mut self,
+        name: Symbol,
+        params: &MalVal,
+        body: &MalVal,
+    ) -> Result<Lambda, MalErr> {
+        let arity = param_arity(params)?;
+        let names = params
+            .seq_iter()
+            .into_iter()
+            .flatten()
+            .filter_map(|p| match p {
+                Sym(s) if *s != symbol::AMP => Some((Some(*s), true)),
+                _ => None,
+            })
+            .collect();
+        let frame = Frame::new(names, true);
+        let (frame_size, body) = self.in_frame(frame, |a| a.analyze(body, None))?;
+        Ok(Lambda {
+            name,
+            arity,
+            frame_size,
+            body,
+        })
+    }
+}
@@ -1,84 +1,136 @@
+use std::cell::RefCell;
+use std::cmp::Ordering;
 use std::fs::File;
 use std::io::Read;
 use std::rc::Rc;
-use std::sync::Mutex;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
+use itertools::Itertools;
+use num_bigint;
+use num_rational::BigRational;
+use num_traits::{pow, FromPrimitive, ToPrimitive, Zero};
+
+use crate::gc::{self, GcStats};
+use crate::lazy::{lazy_cons, lazy_seq};
+use crate::list::MalList;
 use crate::printer::pr_seq;
-use crate::reader::read_str;
-use crate::types::MalErr::ErrMalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{MalArgs, MalRet, MalVal, _assoc, _dissoc, atom, error, func, hash_map};
-
-macro_rules! fn_t_int_int {
-    ($ret:ident, $fn:expr) => {{
-        |a: MalArgs| match (a[0].clone(), a[1].clone()) {
-            (Int(a0), Int(a1)) => Ok($ret($fn(a0, a1))),
-            _ => error("expecting (int,int) args"),
+use crate::reader::{read_all, read_all_from, read_str, read_str_from};
+use crate::symbol::Symbol;
+use crate::types::MalErr::{ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Ratio, Set,
+    Str, Sym, Vector,
+};
+use crate::types::{
+    _assoc, _disj, _dissoc, atom, error, func_arity, hash_map, hash_set, integer, keyword, ratio,
+    MalErr, MalRet, MalSet, MalVal,
+};
+
+macro_rules! fn_arith {
+    ($op:tt, $checked:ident) => {{
+        |x: &MalVal, y: &MalVal| {
+            arith(
+                x,
+                y,
+                i64::$checked,
+                |x, y| Some(x $op y),
+                |x, y| x $op y,
+                |x, y| x $op y,
+            )
+        }
+    }};
+}
+
+// comparisons chain: true when each adjacent pair is ordered
+macro_rules! fn_cmp {
+    ($($ord:ident)|+) => {{
+        |a: &[MalVal]| {
+            for w in a.windows(2) {
+                if !matches!(num_cmp(&w[0], &w[1])?, Some($(Ordering::$ord)|+)) {
+                    return Ok(Bool(false));
+                }
+            }
+            Ok(Bool(true))
         }
     }};
 }
 
 macro_rules! fn_is_type {
   ($($ps:pat),*) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $($ps => true,)* _ => false})) }
+    |a: &[MalVal]| { Ok(Bool(match a[0] { $($ps => true,)* _ => false})) }
   }};
   ($p:pat if $e:expr) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, _ => false})) }
+    |a: &[MalVal]| { Ok(Bool(match a[0] { $p if $e => true, _ => false})) }
   }};
   ($p:pat if $e:expr,$($ps:pat),*) => {{
-    |a:MalArgs| { Ok(Bool(match a[0] { $p if $e => true, $($ps => true,)* _ => false})) }
+    |a: &[MalVal]| { Ok(Bool(match a[0] { $p if $e => true, $($ps => true,)* _ => false})) }
   }};
 }
 
 macro_rules! fn_str {
     ($fn:expr) => {{
-        |a: MalArgs| match a[0].clone() {
+        |a: &[MalVal]| match a[0].clone() {
             Str(a0) => $fn(a0),
             _ => error("expecting (str) arg"),
         }
     }};
 }
 
-fn symbol(a: MalArgs) -> MalRet {
+fn symbol(a: &[MalVal]) -> MalRet {
     match a[0] {
-        Str(ref s) => Ok(Sym(s.to_string())),
+        Str(ref s) => Ok(Sym(Symbol::intern(s))),
         _ => error("illegal symbol call"),
     }
 }
 
-fn readline(a: MalArgs) -> MalRet {
-    lazy_static! {
-        static ref RL: Mutex<Editor<()>> = Mutex::new(Editor::<()>::new());
-    }
-    //let mut rl = Editor::<()>::new();
-
-    match a[0] {
-        Str(ref p) => {
-            //match rl.readline(p) {
-            match RL.lock().unwrap().readline(p) {
-                Ok(mut line) => {
-                    // Remove any trailing \n or \r\n
-                    if line.ends_with('\n') {
+// the line editor is created on first use and keeps its history for
+// the rest of the session
+fn readline() -> MalVal {
+    let rl: RefCell<Option<Editor<()>>> = RefCell::new(None);
+    func_arity("readline", 1, Some(1), move |a| match a[0] {
+        Str(ref p) => match rl
+            .borrow_mut()
+            .get_or_insert_with(Editor::<()>::new)
+            .readline(p)
+        {
+            Ok(mut line) => {
+                // Remove any trailing \n or \r\n
+                if line.ends_with('\n') {
+                    line.pop();
+                    if line.ends_with('\r') {
                         line.pop();
-                        if line.ends_with('\r') {
-                            line.pop();
-                        }
                     }
-                    Ok(Str(line))
                 }
-                Err(ReadlineError::Eof) => Ok(Nil),
-                Err(e) => error(&format!("{:?}", e)),
+                Ok(Str(line))
             }
-        }
+            Err(ReadlineError::Eof) => Ok(Nil),
+            Err(e) => error(&format!("{:?}", e)),
+        },
         _ => error("readline: prompt is not Str"),
+    })
+}
+
+fn read_string(a: &[MalVal]) -> MalRet {
+    match (&a[0], a.get(1)) {
+        (Str(s), None) => read_str(s.to_string()),
+        (Str(s), Some(Str(f))) => read_str_from(s.to_string(), f),
+        _ => error("read-string: expecting (str[, file]) args"),
     }
 }
 
+fn read_string_all(a: &[MalVal]) -> MalRet {
+    let forms = match (&a[0], a.get(1)) {
+        (Str(s), None) => read_all(s).collect::<Result<Vec<MalVal>, _>>()?,
+        (Str(s), Some(Str(f))) => read_all_from(s, f).collect::<Result<Vec<MalVal>, _>>()?,
+        _ => return error("read-string-all: expecting (str[, file]) args"),
+    };
+    Ok(list!(forms))
+}
+
 fn slurp(f: String) -> MalRet {
     let mut s = String::new();
     match File::open(f).and_then(|mut f| f.read_to_string(&mut s)) {
@@ -87,7 +139,7 @@ fn slurp(f: String) -> MalRet {
     }
 }
 
-fn time_ms(_a: MalArgs) -> MalRet {
+fn time_ms(_a: &[MalVal]) -> MalRet {
     let ms_e = match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(d) => d,
         Err(e) => return error(&format!("{:?}", e)),
@@ -97,255 +149,777 @@ fn time_ms(_a: MalArgs) -> MalRet {
     ))
 }
 
-fn get(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Nil, _) => Ok(Nil),
-        (Hash(ref hm, _), Str(ref s)) => match hm.get(s) {
+fn to_bigint(a: &MalVal) -> Option<num_bigint::BigInt> {
+    match a {
+        Int(i) => Some(num_bigint::BigInt::from(*i)),
+        BigInt(i) => Some((**i).clone()),
+        _ => None,
+    }
+}
+
+fn to_ratio(a: &MalVal) -> Option<BigRational> {
+    match a {
+        Ratio(r) => Some((**r).clone()),
+        _ => to_bigint(a).map(BigRational::from_integer),
+    }
+}
+
+fn to_f64(a: &MalVal) -> Option<f64> {
+    match a {
+        Int(i) => Some(*i as f64),
+        BigInt(i) => i.to_f64(),
+        Ratio(r) => r.to_f64(),
+        Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+// The i64 and BigInt ops return None when the result doesn't belong
+// at their level of the tower (overflow, or an inexact division) and
+// it is redone one level up: i64 -> BigInt -> ratio.  BigInts and
+// ratios are contagious, and anything involving a float is done in f64.
+fn arith(
+    a: &MalVal,
+    b: &MalVal,
+    int_op: fn(i64, i64) -> Option<i64>,
+    big_op: fn(&num_bigint::BigInt, &num_bigint::BigInt) -> Option<num_bigint::BigInt>,
+    ratio_op: fn(BigRational, BigRational) -> BigRational,
+    float_op: fn(f64, f64) -> f64,
+) -> MalRet {
+    if let (Int(x), Int(y)) = (a, b) {
+        if let Some(r) = int_op(*x, *y) {
+            return Ok(Int(r));
+        }
+    }
+    match (a, b) {
+        (Float(_), _) | (_, Float(_)) => match (to_f64(a), to_f64(b)) {
+            (Some(x), Some(y)) => Ok(Float(float_op(x, y))),
+            _ => error("expecting (number,number) args"),
+        },
+        (Ratio(_), _) | (_, Ratio(_)) => match (to_ratio(a), to_ratio(b)) {
+            (Some(x), Some(y)) => Ok(ratio(ratio_op(x, y))),
+            _ => error("expecting (number,number) args"),
+        },
+        _ => match (to_bigint(a), to_bigint(b)) {
+            (Some(x), Some(y)) => match big_op(&x, &y) {
+                Some(r) => Ok(integer(r)),
+                None => Ok(ratio(ratio_op(
+                    BigRational::from_integer(x),
+                    BigRational::from_integer(y),
+                ))),
+            },
+            _ => error("expecting (number,number) args"),
+        },
+    }
+}
+
+fn divide(x: &MalVal, y: &MalVal) -> MalRet {
+    // an exact zero divisor is an error; with a float dividend the
+    // division follows IEEE and gives inf or NaN
+    let zero = match y {
+        Int(y) => *y == 0,
+        BigInt(y) => y.is_zero(),
+        Ratio(y) => y.is_zero(),
+        _ => false,
+    };
+    if zero && !matches!(x, Float(_)) {
+        return error("Divide by zero");
+    }
+    arith(
+        x,
+        y,
+        |x, y| match x.checked_rem(y) {
+            Some(0) => x.checked_div(y),
+            _ => None,
+        },
+        |x, y| {
+            if y.is_zero() || !(x % y).is_zero() {
+                None
+            } else {
+                Some(x / y)
+            }
+        },
+        |x, y| x / y,
+        |x, y| x / y,
+    )
+}
+
+// Left fold of a binary op, as in Clojure: (+) is the identity and
+// (- x) is (- identity x), i.e. negation; (/ x) is the reciprocal.
+fn fold_arith(a: &[MalVal], identity: MalVal, op: fn(&MalVal, &MalVal) -> MalRet) -> MalRet {
+    match a.split_first() {
+        None => Ok(identity),
+        Some((x, [])) => op(&identity, x),
+        Some((x, rest)) => rest.iter().try_fold(x.clone(), |acc, y| op(&acc, y)),
+    }
+}
+
+// None if either side is NaN
+fn num_cmp(a: &MalVal, b: &MalVal) -> Result<Option<Ordering>, MalErr> {
+    let not_num = || ErrString("expecting (number,number) args".to_string());
+    match (a, b) {
+        (Int(x), Int(y)) => Ok(x.partial_cmp(y)),
+        (Float(_), _) | (_, Float(_)) => {
+            let (x, y) = (
+                to_f64(a).ok_or_else(not_num)?,
+                to_f64(b).ok_or_else(not_num)?,
+            );
+            Ok(x.partial_cmp(&y))
+        }
+        _ => {
+            let (x, y) = (
+                to_ratio(a).ok_or_else(not_num)?,
+                to_ratio(b).ok_or_else(not_num)?,
+            );
+            Ok(x.partial_cmp(&y))
+        }
+    }
+}
+
+fn int(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Int(_) | BigInt(_) => Ok(a[0].clone()),
+        Ratio(ref r) => Ok(integer(r.trunc().to_integer())),
+        Float(f) if f.is_finite() => match f.to_i64() {
+            Some(i) => Ok(Int(i)),
+            None => Ok(BigInt(Rc::new(num_bigint::BigInt::from_f64(f).unwrap()))),
+        },
+        Float(f) => error(&format!("int: cannot convert {:?}", f)),
+        _ => error("int: expecting number"),
+    }
+}
+
+fn double(a: &[MalVal]) -> MalRet {
+    match to_f64(&a[0]) {
+        Some(f) => Ok(Float(f)),
+        None => error("double: expecting number"),
+    }
+}
+
+fn numerator(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Ratio(ref r) => Ok(integer(r.numer().clone())),
+        Int(_) | BigInt(_) => Ok(a[0].clone()),
+        _ => error("numerator: expecting rational"),
+    }
+}
+
+fn denominator(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Ratio(ref r) => Ok(integer(r.denom().clone())),
+        Int(_) | BigInt(_) => Ok(Int(1)),
+        _ => error("denominator: expecting rational"),
+    }
+}
+
+// Floats become the ratio of the shortest decimal that reads back as
+// the same float, so (rationalize 0.1) is 1/10 rather than the exact
+// binary value 3602879701896397/36028797018963968.
+fn rationalize(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Float(f) if f.is_finite() => {
+            let s = format!("{}", f.abs());
+            let (whole, frac) = s.split_once('.').unwrap_or((&s, ""));
+            let n: num_bigint::BigInt = format!("{}{}", whole, frac).parse().unwrap();
+            let d = pow(num_bigint::BigInt::from(10), frac.len());
+            let r = BigRational::new(n, d);
+            Ok(ratio(if f < 0.0 { -r } else { r }))
+        }
+        Float(f) => error(&format!("rationalize: cannot convert {:?}", f)),
+        Int(_) | BigInt(_) | Ratio(_) => Ok(a[0].clone()),
+        _ => error("rationalize: expecting number"),
+    }
+}
+
+fn get(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Nil => Ok(Nil),
+        Hash(ref hm, _) => match hm.get(&a[1]) {
             Some(mv) => Ok(mv.clone()),
             None => Ok(Nil),
         },
+        Set(ref s, _) if s.contains(&a[1]) => Ok(a[1].clone()),
+        Set(_, _) => Ok(Nil),
         _ => error("illegal get args"),
     }
 }
 
-fn assoc(a: MalArgs) -> MalRet {
+fn assoc(a: &[MalVal]) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _assoc((**hm).clone(), a[1..].to_vec()),
+        Vector(ref v, _) => {
+            if a.len() % 2 != 1 {
+                return error("odd number of elements");
+            }
+            let mut v = (**v).clone();
+            for (k, mv) in a[1..].iter().tuples() {
+                match *k {
+                    Int(i) if (i as usize) < v.len() => {
+                        v.set(i as usize, mv.clone());
+                    }
+                    Int(i) if i as usize == v.len() => v.push_back(mv.clone()),
+                    _ => return error("assoc: vector index out of range"),
+                }
+            }
+            Ok(Vector(Rc::new(v), Rc::new(Nil)))
+        }
         _ => error("assoc on non-Hash Map"),
     }
 }
 
-fn dissoc(a: MalArgs) -> MalRet {
+fn dissoc(a: &[MalVal]) -> MalRet {
     match a[0] {
         Hash(ref hm, _) => _dissoc((**hm).clone(), a[1..].to_vec()),
         _ => error("dissoc on non-Hash Map"),
     }
 }
 
-fn contains_q(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (Hash(ref hm, _), Str(ref s)) => Ok(Bool(hm.contains_key(s))),
+fn contains_q(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Hash(ref hm, _) => Ok(Bool(hm.contains_key(&a[1]))),
+        Set(ref s, _) => Ok(Bool(s.contains(&a[1]))),
         _ => error("illegal get args"),
     }
 }
 
-fn keys(a: MalArgs) -> MalRet {
+fn keys(a: &[MalVal]) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.keys().map(|k| { Str(k.to_string()) }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.keys().cloned().collect())),
         _ => error("keys requires Hash Map"),
     }
 }
 
-fn vals(a: MalArgs) -> MalRet {
+fn vals(a: &[MalVal]) -> MalRet {
     match a[0] {
-        Hash(ref hm, _) => Ok(list!(hm.values().map(|v| { v.clone() }).collect())),
+        Hash(ref hm, _) => Ok(list!(hm.values().cloned().collect())),
         _ => error("keys requires Hash Map"),
     }
 }
 
-fn vec(a: MalArgs) -> MalRet {
+fn set(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Set(ref s, _) => Ok(Set(s.clone(), Rc::new(Nil))),
+        Nil => Ok(hash_set(vec![])),
+        LazySeq(_) => Ok(hash_set(a[0].walk().collect::<Result<_, _>>()?)),
+        _ => match a[0].seq_iter() {
+            Some(it) => Ok(hash_set(it.cloned().collect())),
+            None => error("set: called with non-seq"),
+        },
+    }
+}
+
+fn disj(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Set(ref s, _) => Ok(_disj((**s).clone(), a[1..].to_vec())),
+        _ => error("disj on non-set"),
+    }
+}
+
+fn set_contains(s: &MalVal, x: &MalVal) -> bool {
+    matches!(s, Set(s, _) if s.contains(x))
+}
+
+fn union(a: &[MalVal]) -> MalRet {
+    let mut sets = vec![];
+    for s in a.iter() {
+        match s {
+            Set(s, _) => sets.push(s),
+            _ => return error("union: called with non-set"),
+        }
+    }
+    // the others are added to (a copy of) the largest, sharing its nodes
+    let largest = (0..sets.len()).max_by_key(|&i| sets[i].len());
+    let mut u = match largest {
+        Some(i) => (**sets.swap_remove(i)).clone(),
+        None => MalSet::default(),
+    };
+    for s in sets {
+        u.extend(s.iter().cloned());
+    }
+    Ok(Set(Rc::new(u), Rc::new(Nil)))
+}
+
+fn intersection(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Set(ref s, _) if a.iter().all(|o| matches!(o, Set(_, _))) => Ok(hash_set(
+            s.iter()
+                .filter(|x| a[1..].iter().all(|o| set_contains(o, x)))
+                .cloned()
+                .collect(),
+        )),
+        _ => error("intersection: called with non-set"),
+    }
+}
+
+fn difference(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        Set(ref s, _) if a.iter().all(|o| matches!(o, Set(_, _))) => Ok(hash_set(
+            s.iter()
+                .filter(|x| !a[1..].iter().any(|o| set_contains(o, x)))
+                .cloned()
+                .collect(),
+        )),
+        _ => error("difference: called with non-set"),
+    }
+}
+
+fn vec(a: &[MalVal]) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) => Ok(vector!(v.to_vec())),
+        Vector(ref v, _) => Ok(Vector(v.clone(), Rc::new(Nil))),
+        List(ref l, _) => Ok(Vector(Rc::new(l.iter().cloned().collect()), Rc::new(Nil))),
+        LazySeq(_) => Ok(vector!(a[0].walk().collect::<Result<_, _>>()?)),
         _ => error("non-seq passed to vec"),
     }
 }
 
-fn cons(a: MalArgs) -> MalRet {
-    match a[1].clone() {
-        List(v, _) | Vector(v, _) => {
-            let mut new_v = vec![a[0].clone()];
-            new_v.extend_from_slice(&v);
-            Ok(list!(new_v.to_vec()))
+fn cons(a: &[MalVal]) -> MalRet {
+    match a[1] {
+        List(ref l, _) => Ok(List(l.cons(a[0].clone()), Rc::new(Nil))),
+        Vector(ref v, _) => {
+            let l: MalList = v.iter().cloned().collect();
+            Ok(List(l.cons(a[0].clone()), Rc::new(Nil)))
         }
+        LazySeq(_) => Ok(lazy_cons(a[0].clone(), a[1].clone())),
+        Nil => Ok(list!(vec![a[0].clone()])),
         _ => error("cons expects seq as second arg"),
     }
 }
 
-fn concat(a: MalArgs) -> MalRet {
+fn concat(a: &[MalVal]) -> MalRet {
+    if !a.iter().all(|seq| seq.sequential_q() || matches!(seq, Nil)) {
+        return error("non-seq passed to concat");
+    }
+    // lazy if any of the seqs is, as it may be infinite
+    if a.iter().any(|seq| matches!(seq, LazySeq(_))) {
+        return Ok(concat_seq(Nil, a.iter().cloned().collect()));
+    }
     let mut new_v = vec![];
     for seq in a.iter() {
-        match seq {
-            List(v, _) | Vector(v, _) => new_v.extend_from_slice(v),
-            _ => return error("non-seq passed to concat"),
-        }
+        new_v.extend(seq.seq_iter().into_iter().flatten().cloned());
     }
-    Ok(list!(new_v.to_vec()))
+    Ok(list!(new_v))
 }
 
-fn nth(a: MalArgs) -> MalRet {
-    match (a[0].clone(), a[1].clone()) {
-        (List(seq, _), Int(idx)) | (Vector(seq, _), Int(idx)) => {
-            if seq.len() <= idx as usize {
-                return error("nth: index out of range");
-            }
-            Ok(seq[idx as usize].clone())
-        }
-        _ => error("invalid args to nth"),
+fn nth(a: &[MalVal]) -> MalRet {
+    let item = match (&a[0], &a[1]) {
+        (List(l, _), Int(idx)) => l.get(*idx as usize).cloned(),
+        (Vector(v, _), Int(idx)) => v.get(*idx as usize).cloned(),
+        (LazySeq(_), Int(idx)) if *idx >= 0 => a[0].walk().nth(*idx as usize).transpose()?,
+        (LazySeq(_), Int(_)) => None,
+        _ => return error("invalid args to nth"),
+    };
+    match item {
+        Some(mv) => Ok(mv),
+        None => error("nth: index out of range"),
     }
 }
 
-fn first(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) if seq.len() == 0 => Ok(Nil),
-        List(ref seq, _) | Vector(ref seq, _) => Ok(seq[0].clone()),
+fn first(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        List(ref l, _) => Ok(l.first().cloned().unwrap_or(Nil)),
+        Vector(ref v, _) => Ok(v.front().cloned().unwrap_or(Nil)),
+        LazySeq(ref l) => Ok(l.realize()?.map(|(x, _)| x).unwrap_or(Nil)),
         Nil => Ok(Nil),
         _ => error("invalid args to first"),
     }
 }
 
-fn rest(a: MalArgs) -> MalRet {
-    match a[0].clone() {
-        List(ref seq, _) | Vector(ref seq, _) => {
-            if seq.len() > 1 {
-                Ok(list!(seq[1..].to_vec()))
-            } else {
-                Ok(list![])
-            }
-        }
+fn rest(a: &[MalVal]) -> MalRet {
+    match a[0] {
+        List(ref l, _) => Ok(List(l.rest(), Rc::new(Nil))),
+        Vector(ref v, _) => Ok(list!(v.iter().skip(1).cloned().collect())),
+        LazySeq(ref l) => match l.realize()? {
+            Some((_, rest @ List(_, _))) | Some((_, rest @ LazySeq(_))) => Ok(rest),
+            Some((_, rest)) => rest.walk().collect::<Result<_, _>>().map(|v| list!(v)),
+            None => Ok(list![]),
+        },
         Nil => Ok(list![]),
         _ => error("invalid args to first"),
     }
 }
 
-fn apply(a: MalArgs) -> MalRet {
-    match a[a.len() - 1] {
-        List(ref v, _) | Vector(ref v, _) => {
+fn apply(a: &[MalVal]) -> MalRet {
+    match a[a.len() - 1].seq_iter() {
+        Some(v) => {
             let f = &a[0];
             let mut fargs = a[1..a.len() - 1].to_vec();
-            fargs.extend_from_slice(&v);
+            fargs.extend(v.cloned());
             f.apply(fargs)
         }
-        _ => error("apply called with non-seq"),
+        None if matches!(a[a.len() - 1], LazySeq(_)) => {
+            let mut fargs = a[1..a.len() - 1].to_vec();
+            for mv in a[a.len() - 1].walk() {
+                fargs.push(mv?)
+            }
+            a[0].apply(fargs)
+        }
+        None => error("apply called with non-seq"),
     }
 }
 
-fn map(a: MalArgs) -> MalRet {
-    match a[1] {
-        List(ref v, _) | Vector(ref v, _) => {
+// lists and vectors are mapped eagerly, as mal always has; lazy
+// sequences lazily
+fn map(a: &[MalVal]) -> MalRet {
+    if let LazySeq(_) = a[1] {
+        return Ok(map_seq(a[0].clone(), a[1].clone()));
+    }
+    match a[1].seq_iter() {
+        Some(v) => {
             let mut res = vec![];
-            for mv in v.iter() {
+            for mv in v {
                 res.push(a[0].apply(vec![mv.clone()])?)
             }
             Ok(list!(res))
         }
-        _ => error("map called with non-seq"),
+        None => error("map called with non-seq"),
     }
 }
 
-fn conj(a: MalArgs) -> MalRet {
+fn conj(a: &[MalVal]) -> MalRet {
     match a[0] {
-        List(ref v, _) => {
-            let sl = a[1..]
-                .iter()
-                .rev()
-                .map(|a| a.clone())
-                .collect::<Vec<MalVal>>();
-            Ok(list!([&sl[..], v].concat()))
+        List(ref l, _) => {
+            let l = a[1..].iter().fold(l.clone(), |l, mv| l.cons(mv.clone()));
+            Ok(List(l, Rc::new(Nil)))
+        }
+        Vector(ref v, _) => {
+            let mut v = (**v).clone();
+            v.extend(a[1..].iter().cloned());
+            Ok(Vector(Rc::new(v), Rc::new(Nil)))
+        }
+        LazySeq(_) => Ok(a[1..]
+            .iter()
+            .fold(a[0].clone(), |s, mv| lazy_cons(mv.clone(), s))),
+        Set(ref s, _) => {
+            let mut s = (**s).clone();
+            s.extend(a[1..].iter().cloned());
+            Ok(Set(Rc::new(s), Rc::new(Nil)))
         }
-        Vector(ref v, _) => Ok(vector!([v, &a[1..]].concat())),
         _ => error("conj: called with non-seq"),
     }
 }
 
-fn seq(a: MalArgs) -> MalRet {
+fn seq(a: &[MalVal]) -> MalRet {
     match a[0] {
-        List(ref v, _) | Vector(ref v, _) if v.len() == 0 => Ok(Nil),
-        List(ref v, _) | Vector(ref v, _) => Ok(list!(v.to_vec())),
-        Str(ref s) if s.len() == 0 => Ok(Nil),
-        Str(ref s) if !a[0].keyword_q() => {
-            Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect()))
-        }
+        List(ref l, _) if l.is_empty() => Ok(Nil),
+        List(ref l, _) => Ok(List(l.clone(), Rc::new(Nil))),
+        Vector(ref v, _) if v.is_empty() => Ok(Nil),
+        Vector(ref v, _) => Ok(list!(v.iter().cloned().collect())),
+        LazySeq(ref l) => match l.realize()? {
+            Some(_) => Ok(a[0].clone()),
+            None => Ok(Nil),
+        },
+        Set(ref s, _) if s.is_empty() => Ok(Nil),
+        Set(ref s, _) => Ok(list!(s.iter().cloned().collect())),
+        Str(ref s) if s.is_empty() => Ok(Nil),
+        Str(ref s) => Ok(list!(s.chars().map(|c| { Str(c.to_string()) }).collect())),
         Nil => Ok(Nil),
         _ => error("seq: called with non-seq"),
     }
 }
 
+fn truthy(mv: &MalVal) -> bool {
+    !matches!(mv, Nil | Bool(false))
+}
+
+fn int_arg(mv: &MalVal) -> Result<i64, MalErr> {
+    match mv {
+        Int(n) => Ok(*n),
+        _ => Err(ErrString("expecting int arg".to_string())),
+    }
+}
+
+// Lazy sequence builders. Each returns an unrealized sequence whose
+// thunk produces one cell and defers the rest to another builder.
+
+fn range_seq(start: MalVal, end: Option<MalVal>, step: MalVal) -> MalVal {
+    lazy_seq(move || {
+        if let Some(ref end) = end {
+            let dir = match num_cmp(&step, &Int(0))? {
+                Some(Ordering::Less) => Ordering::Greater,
+                _ => Ordering::Less,
+            };
+            if num_cmp(&start, end)? != Some(dir) {
+                return Ok(Nil);
+            }
+        }
+        let next = fn_arith!(+, checked_add)(&start, &step)?;
+        Ok(lazy_cons(
+            start.clone(),
+            range_seq(next, end.clone(), step.clone()),
+        ))
+    })
+}
+
+fn concat_seq(cur: MalVal, more: MalList) -> MalVal {
+    lazy_seq(move || {
+        let (mut cur, mut more) = (cur.clone(), more.clone());
+        loop {
+            if let Some((x, rest)) = cur.uncons()? {
+                return Ok(lazy_cons(x, concat_seq(rest, more)));
+            }
+            cur = match more.first() {
+                Some(next) => next.clone(),
+                None => return Ok(Nil),
+            };
+            more = more.rest();
+        }
+    })
+}
+
+fn iterate_seq(f: MalVal, x: MalVal) -> MalVal {
+    let (f2, x2) = (f.clone(), x.clone());
+    let rest = lazy_seq(move || Ok(iterate_seq(f2.clone(), f2.apply(vec![x2.clone()])?)));
+    lazy_cons(x, rest)
+}
+
+fn repeat_seq(x: MalVal) -> MalVal {
+    lazy_seq(move || Ok(lazy_cons(x.clone(), repeat_seq(x.clone()))))
+}
+
+fn cycle_seq(cur: MalVal, coll: MalVal) -> MalVal {
+    lazy_seq(move || match cur.uncons()? {
+        Some((x, rest)) => Ok(lazy_cons(x, cycle_seq(rest, coll.clone()))),
+        None => match coll.uncons()? {
+            Some((x, rest)) => Ok(lazy_cons(x, cycle_seq(rest, coll.clone()))),
+            None => Ok(Nil),
+        },
+    })
+}
+
+fn map_seq(f: MalVal, coll: MalVal) -> MalVal {
+    lazy_seq(move || match coll.uncons()? {
+        Some((x, rest)) => Ok(lazy_cons(f.apply(vec![x])?, map_seq(f.clone(), rest))),
+        None => Ok(Nil),
+    })
+}
+
+fn filter_seq(pred: MalVal, coll: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let mut cur = coll.clone();
+        while let Some((x, rest)) = cur.uncons()? {
+            if truthy(&pred.apply(vec![x.clone()])?) {
+                return Ok(lazy_cons(x, filter_seq(pred.clone(), rest)));
+            }
+            cur = rest;
+        }
+        Ok(Nil)
+    })
+}
+
+fn take_seq(n: i64, coll: MalVal) -> MalVal {
+    lazy_seq(move || {
+        if n <= 0 {
+            return Ok(Nil);
+        }
+        match coll.uncons()? {
+            Some((x, rest)) => Ok(lazy_cons(x, take_seq(n - 1, rest))),
+            None => Ok(Nil),
+        }
+    })
+}
+
+fn drop_seq(n: i64, coll: MalVal) -> MalVal {
+    lazy_seq(move || {
+        let mut cur = coll.clone();
+        for _ in 0..n {
+            match cur.uncons()? {
+                Some((_, rest)) => cur = rest,
+                None => return Ok(Nil),
+            }
+        }
+        Ok(cur)
+    })
+}
+
+fn take_while_seq(pred: MalVal, coll: MalVal) -> MalVal {
+    lazy_seq(move || match coll.uncons()? {
+        Some((x, rest)) if truthy(&pred.apply(vec![x.clone()])?) => {
+            Ok(lazy_cons(x, take_while_seq(pred.clone(), rest)))
+        }
+        _ => Ok(Nil),
+    })
+}
+
+fn range(a: &[MalVal]) -> MalRet {
+    match a.len() {
+        0 => Ok(range_seq(Int(0), None, Int(1))),
+        1 => Ok(range_seq(Int(0), Some(a[0].clone()), Int(1))),
+        2 => Ok(range_seq(a[0].clone(), Some(a[1].clone()), Int(1))),
+        3 => Ok(range_seq(a[0].clone(), Some(a[1].clone()), a[2].clone())),
+        _ => error("range: expecting at most 3 args"),
+    }
+}
+
+fn repeat(a: &[MalVal]) -> MalRet {
+    match a.len() {
+        1 => Ok(repeat_seq(a[0].clone())),
+        _ => Ok(take_seq(int_arg(&a[0])?, repeat_seq(a[1].clone()))),
+    }
+}
+
+fn doall(a: &[MalVal]) -> MalRet {
+    for mv in a[0].walk() {
+        mv?;
+    }
+    Ok(a[0].clone())
+}
+
+// what (gc) returns: the envs and atoms still alive, and those freed
+fn gc_stats(s: GcStats) -> MalRet {
+    let count = |k: &str, n: usize| vec![keyword(k), Int(n as i64)];
+    hash_map(
+        [
+            count("envs", s.envs),
+            count("atoms", s.atoms),
+            count("freed-envs", s.freed_envs),
+            count("freed-atoms", s.freed_atoms),
+        ]
+        .concat(),
+    )
+}
+
+type Builtin = fn(&[MalVal]) -> MalRet;
+
+// each builtin declares its min and max number of args (None for
+// variadic), so the bodies can index their args without checking
 pub fn ns() -> Vec<(&'static str, MalVal)> {
-    vec![
-        ("=", func(|a| Ok(Bool(a[0] == a[1])))),
-        ("throw", func(|a| Err(ErrMalVal(a[0].clone())))),
-        ("nil?", func(fn_is_type!(Nil))),
-        ("true?", func(fn_is_type!(Bool(true)))),
-        ("false?", func(fn_is_type!(Bool(false)))),
-        ("symbol", func(symbol)),
-        ("symbol?", func(fn_is_type!(Sym(_)))),
+    let builtins: Vec<(&'static str, usize, Option<usize>, Builtin)> = vec![
+        ("=", 1, None, |a| {
+            Ok(Bool(a.windows(2).all(|w| w[0] == w[1])))
+        }),
+        ("not=", 1, None, |a| {
+            Ok(Bool(!a.windows(2).all(|w| w[0] == w[1])))
+        }),
+        ("throw", 1, Some(1), |a| Err(ErrMalVal(a[0].clone()))),
+        ("nil?", 1, Some(1), fn_is_type!(Nil)),
+        ("true?", 1, Some(1), fn_is_type!(Bool(true))),
+        ("false?", 1, Some(1), fn_is_type!(Bool(false))),
+        ("symbol", 1, Some(1), symbol),
+        ("symbol?", 1, Some(1), fn_is_type!(Sym(_))),
+        ("string?", 1, Some(1), fn_is_type!(Str(_))),
+        ("keyword", 1, Some(1), |a| a[0].keyword()),
+        ("keyword?", 1, Some(1), fn_is_type!(Keyword(_))),
         (
-            "string?",
-            func(fn_is_type!(Str(ref s) if !s.starts_with("\u{29e}"))),
+            "number?",
+            1,
+            Some(1),
+            fn_is_type!(Int(_), BigInt(_), Ratio(_), Float(_)),
         ),
-        ("keyword", func(|a| a[0].keyword())),
-        (
-            "keyword?",
-            func(fn_is_type!(Str(ref s) if s.starts_with("\u{29e}"))),
-        ),
-        ("number?", func(fn_is_type!(Int(_)))),
+        ("float?", 1, Some(1), fn_is_type!(Float(_))),
         (
             "fn?",
-            func(fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_))),
+            1,
+            Some(1),
+            fn_is_type!(MalFunc{is_macro,..} if !is_macro,Func(_,_)),
         ),
         (
             "macro?",
-            func(fn_is_type!(MalFunc{is_macro,..} if is_macro)),
-        ),
-        ("pr-str", func(|a| Ok(Str(pr_seq(&a, true, "", "", " "))))),
-        ("str", func(|a| Ok(Str(pr_seq(&a, false, "", "", ""))))),
-        (
-            "prn",
-            func(|a| {
-                println!("{}", pr_seq(&a, true, "", "", " "));
-                Ok(Nil)
-            }),
-        ),
-        (
-            "println",
-            func(|a| {
-                println!("{}", pr_seq(&a, false, "", "", " "));
-                Ok(Nil)
-            }),
+            1,
+            Some(1),
+            fn_is_type!(MalFunc{is_macro,..} if is_macro),
         ),
-        ("read-string", func(fn_str!(|s| { read_str(s) }))),
-        ("readline", func(readline)),
-        ("slurp", func(fn_str!(|f| { slurp(f) }))),
-        ("<", func(fn_t_int_int!(Bool, |i, j| { i < j }))),
-        ("<=", func(fn_t_int_int!(Bool, |i, j| { i <= j }))),
-        (">", func(fn_t_int_int!(Bool, |i, j| { i > j }))),
-        (">=", func(fn_t_int_int!(Bool, |i, j| { i >= j }))),
-        ("+", func(fn_t_int_int!(Int, |i, j| { i + j }))),
-        ("-", func(fn_t_int_int!(Int, |i, j| { i - j }))),
-        ("*", func(fn_t_int_int!(Int, |i, j| { i * j }))),
-        ("/", func(fn_t_int_int!(Int, |i, j| { i / j }))),
-        ("time-ms", func(time_ms)),
-        ("sequential?", func(fn_is_type!(List(_, _), Vector(_, _)))),
-        ("list", func(|a| Ok(list!(a)))),
-        ("list?", func(fn_is_type!(List(_, _)))),
-        ("vector", func(|a| Ok(vector!(a)))),
-        ("vector?", func(fn_is_type!(Vector(_, _)))),
-        ("hash-map", func(|a| hash_map(a))),
-        ("map?", func(fn_is_type!(Hash(_, _)))),
-        ("assoc", func(assoc)),
-        ("dissoc", func(dissoc)),
-        ("get", func(get)),
-        ("contains?", func(contains_q)),
-        ("keys", func(keys)),
-        ("vals", func(vals)),
-        ("vec", func(vec)),
-        ("cons", func(cons)),
-        ("concat", func(concat)),
-        ("empty?", func(|a| a[0].empty_q())),
-        ("nth", func(nth)),
-        ("first", func(first)),
-        ("rest", func(rest)),
-        ("count", func(|a| a[0].count())),
-        ("apply", func(apply)),
-        ("map", func(map)),
-        ("conj", func(conj)),
-        ("seq", func(seq)),
-        ("meta", func(|a| a[0].get_meta())),
-        ("with-meta", func(|a| a[0].clone().with_meta(&a[1]))),
-        ("atom", func(|a| Ok(atom(&a[0])))),
-        ("atom?", func(fn_is_type!(Atom(_)))),
-        ("deref", func(|a| a[0].deref())),
-        ("reset!", func(|a| a[0].reset_bang(&a[1]))),
-        ("swap!", func(|a| a[0].swap_bang(&a[1..].to_vec()))),
-    ]
+        ("pr-str", 0, None, |a| {
+            Ok(Str(pr_seq(a, true, "", "", " ")?))
+        }),
+        ("str", 0, None, |a| Ok(Str(pr_seq(a, false, "", "", "")?))),
+        ("prn", 0, None, |a| {
+            println!("{}", pr_seq(a, true, "", "", " ")?);
+            Ok(Nil)
+        }),
+        ("println", 0, None, |a| {
+            println!("{}", pr_seq(a, false, "", "", " ")?);
+            Ok(Nil)
+        }),
+        ("read-string", 1, Some(2), read_string),
+        ("read-string-all", 1, Some(2), read_string_all),
+        ("slurp", 1, Some(1), fn_str!(|f| { slurp(f) })),
+        ("<", 1, None, fn_cmp!(Less)),
+        ("<=", 1, None, fn_cmp!(Less | Equal)),
+        (">", 1, None, fn_cmp!(Greater)),
+        (">=", 1, None, fn_cmp!(Greater | Equal)),
+        ("+", 0, None, |a| {
+            fold_arith(a, Int(0), fn_arith!(+, checked_add))
+        }),
+        ("-", 1, None, |a| {
+            fold_arith(a, Int(0), fn_arith!(-, checked_sub))
+        }),
+        ("*", 0, None, |a| {
+            fold_arith(a, Int(1), fn_arith!(*, checked_mul))
+        }),
+        ("/", 1, None, |a| fold_arith(a, Int(1), divide)),
+        ("int", 1, Some(1), int),
+        ("double", 1, Some(1), double),
+        ("numerator", 1, Some(1), numerator),
+        ("denominator", 1, Some(1), denominator),
+        ("rationalize", 1, Some(1), rationalize),
+        ("time-ms", 0, Some(0), time_ms),
+        ("sequential?", 1, Some(1), |a| Ok(Bool(a[0].sequential_q()))),
+        ("list", 0, None, |a| Ok(list!(a.to_vec()))),
+        ("list?", 1, Some(1), fn_is_type!(List(_, _))),
+        ("vector", 0, None, |a| Ok(vector!(a.to_vec()))),
+        ("vector?", 1, Some(1), fn_is_type!(Vector(_, _))),
+        ("hash-map", 0, None, |a| hash_map(a.to_vec())),
+        ("map?", 1, Some(1), fn_is_type!(Hash(_, _))),
+        ("hash-set", 0, None, |a| Ok(hash_set(a.to_vec()))),
+        ("set", 1, Some(1), set),
+        ("set?", 1, Some(1), fn_is_type!(Set(_, _))),
+        ("disj", 1, None, disj),
+        ("union", 0, None, union),
+        ("intersection", 1, None, intersection),
+        ("difference", 1, None, difference),
+        ("assoc", 1, None, assoc),
+        ("dissoc", 1, None, dissoc),
+        ("get", 2, Some(2), get),
+        ("contains?", 2, Some(2), contains_q),
+        ("keys", 1, Some(1), keys),
+        ("vals", 1, Some(1), vals),
+        ("vec", 1, Some(1), vec),
+        ("cons", 2, Some(2), cons),
+        ("concat", 0, None, concat),
+        ("empty?", 1, Some(1), |a| a[0].empty_q()),
+        ("nth", 2, Some(2), nth),
+        ("first", 1, Some(1), first),
+        ("rest", 1, Some(1), rest),
+        ("count", 1, Some(1), |a| a[0].count()),
+        ("apply", 2, None, apply),
+        ("map", 2, Some(2), map),
+        ("range", 0, Some(3), range),
+        ("iterate", 2, Some(2), |a| {
+            Ok(iterate_seq(a[0].clone(), a[1].clone()))
+        }),
+        ("repeat", 1, Some(2), repeat),
+        ("cycle", 1, Some(1), |a| {
+            Ok(cycle_seq(a[0].clone(), a[0].clone()))
+        }),
+        ("filter", 2, Some(2), |a| {
+            Ok(filter_seq(a[0].clone(), a[1].clone()))
+        }),
+        ("take", 2, Some(2), |a| {
+            Ok(take_seq(int_arg(&a[0])?, a[1].clone()))
+        }),
+        ("drop", 2, Some(2), |a| {
+            Ok(drop_seq(int_arg(&a[0])?, a[1].clone()))
+        }),
+        ("take-while", 2, Some(2), |a| {
+            Ok(take_while_seq(a[0].clone(), a[1].clone()))
+        }),
+        ("doall", 1, Some(1), doall),
+        ("conj", 1, None, conj),
+        ("seq", 1, Some(1), seq),
+        ("meta", 1, Some(1), |a| a[0].get_meta()),
+        ("with-meta", 2, Some(2), |a| a[0].clone().with_meta(&a[1])),
+        ("atom", 1, Some(1), |a| Ok(atom(&a[0]))),
+        ("atom?", 1, Some(1), fn_is_type!(Atom(_))),
+        ("deref", 1, Some(1), |a| a[0].deref()),
+        ("reset!", 2, Some(2), |a| a[0].reset_bang(&a[1])),
+        ("swap!", 2, None, |a| a[0].swap_bang(&a[1..].to_vec())),
+        ("gc", 0, Some(0), |_| gc_stats(gc::collect())),
+    ];
+    let mut ns: Vec<(&'static str, MalVal)> = builtins
+        .into_iter()
+        .map(|(name, min, max, f)| (name, func_arity(name, min, max, f)))
+        .collect();
+    // builtins that keep state between calls
+    ns.push(("readline", readline()));
+    ns
 }
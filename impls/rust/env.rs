@@ -3,13 +3,17 @@ use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
+use crate::gc;
+use crate::symbol::{self, Symbol};
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{List, Nil, Sym, Vector};
+use crate::types::MalVal::Sym;
 use crate::types::{error, MalErr, MalRet, MalVal};
 
 #[derive(Debug)]
 pub struct EnvStruct {
-    data: RefCell<FnvHashMap<String, MalVal>>,
+    pub data: RefCell<FnvHashMap<Symbol, MalVal>>,
+    // the locals of analyzed code, addressed by position (see analyze.rs)
+    pub slots: RefCell<Vec<MalVal>>,
     pub outer: Option<Env>,
 }
 
@@ -19,36 +23,103 @@ pub type Env = Rc<EnvStruct>;
 // a deftype (i.e. Env)
 
 pub fn env_new(outer: Option<Env>) -> Env {
-    Rc::new(EnvStruct {
+    let env = Rc::new(EnvStruct {
         data: RefCell::new(FnvHashMap::default()),
-        outer: outer,
-    })
+        slots: RefCell::new(vec![]),
+        outer,
+    });
+    gc::track_env(&env);
+    env
+}
+
+pub fn env_frame(outer: Env, slots: Vec<MalVal>) -> Env {
+    let env = Rc::new(EnvStruct {
+        data: RefCell::new(FnvHashMap::default()),
+        slots: RefCell::new(slots),
+        outer: Some(outer),
+    });
+    gc::track_env(&env);
+    env
+}
+
+// The number of args a fn* parameter list accepts: the fixed params,
+// and no max when it ends in `& rest`.  Checked when fn* is evaluated
+// and again by env_bind on each call.
+pub fn param_arity(params: &MalVal) -> Result<(usize, Option<usize>), MalErr> {
+    let binds = match params.seq_iter() {
+        Some(binds) => binds,
+        None => return Err(ErrString("fn* params must be a list or vector".to_string())),
+    };
+    let len = binds.len();
+    for (i, b) in binds.enumerate() {
+        match b {
+            Sym(symbol::AMP) => {
+                return match params.seq_iter().and_then(|mut b| b.nth(i + 1)) {
+                    Some(Sym(rest)) if *rest != symbol::AMP && i + 2 == len => Ok((i, None)),
+                    _ => Err(ErrString(
+                        "fn* params: & must be followed by exactly one symbol".to_string(),
+                    )),
+                };
+            }
+            Sym(_) => (),
+            _ => {
+                return Err(ErrString(format!(
+                    "fn* params must be symbols, got {}",
+                    b.pr_str(true)
+                )))
+            }
+        }
+    }
+    Ok((len, Some(len)))
+}
+
+pub fn check_arity(
+    name: Symbol,
+    (min, max): (usize, Option<usize>),
+    n: usize,
+) -> Result<(), MalErr> {
+    if n < min || max.is_some_and(|max| n > max) {
+        return Err(ErrString(format!(
+            "Wrong number of args ({}) passed to {}; expected {}",
+            n,
+            name,
+            match max {
+                Some(max) => max.to_string(),
+                None => format!("at least {}", min),
+            }
+        )));
+    }
+    Ok(())
 }
 
 // TODO: mbinds and exprs as & types
-pub fn env_bind(outer: Option<Env>, mbinds: MalVal, exprs: Vec<MalVal>) -> Result<Env, MalErr> {
+pub fn env_bind(
+    outer: Option<Env>,
+    name: Symbol,
+    mbinds: MalVal,
+    exprs: Vec<MalVal>,
+) -> Result<Env, MalErr> {
+    check_arity(name, param_arity(&mbinds)?, exprs.len())?;
     let env = env_new(outer);
-    match mbinds {
-        List(binds, _) | Vector(binds, _) => {
-            for (i, b) in binds.iter().enumerate() {
-                match b {
-                    Sym(s) if s == "&" => {
-                        env_set(&env, binds[i + 1].clone(), list!(exprs[i..].to_vec()))?;
-                        break;
-                    }
-                    _ => {
-                        env_set(&env, b.clone(), exprs[i].clone())?;
-                    }
+    let mut binds = mbinds.seq_iter().into_iter().flatten().enumerate();
+    while let Some((i, b)) = binds.next() {
+        match b {
+            Sym(symbol::AMP) => {
+                if let Some((_, rest)) = binds.next() {
+                    env_set(&env, rest.clone(), list!(exprs[i..].to_vec()))?;
                 }
+                break;
+            }
+            _ => {
+                env_set(&env, b.clone(), exprs[i].clone())?;
             }
-            Ok(env)
         }
-        _ => Err(ErrString("env_bind binds not List/Vector".to_string())),
     }
+    Ok(env)
 }
 
-pub fn env_find(env: &Env, key: &str) -> Option<Env> {
-    match (env.data.borrow().contains_key(key), env.outer.clone()) {
+pub fn env_find(env: &Env, key: Symbol) -> Option<Env> {
+    match (env.data.borrow().contains_key(&key), env.outer.clone()) {
         (true, _) => Some(env.clone()),
         (false, Some(o)) => env_find(&o, key),
         _ => None,
@@ -57,23 +128,26 @@ pub fn env_find(env: &Env, key: &str) -> Option<Env> {
 
 pub fn env_get(env: &Env, key: &MalVal) -> MalRet {
     match key {
-        Sym(ref s) => match env_find(env, s) {
-            Some(e) => Ok(e
-                .data
-                .borrow()
-                .get(s)
-                .ok_or(ErrString(format!("'{}' not found", s)))?
-                .clone()),
-            _ => error(&format!("'{}' not found", s)),
-        },
+        Sym(s) => {
+            let mut env = env;
+            loop {
+                if let Some(v) = env.data.borrow().get(s) {
+                    return Ok(v.clone());
+                }
+                match env.outer {
+                    Some(ref o) => env = o,
+                    None => return error(&format!("'{}' not found", s)),
+                }
+            }
+        }
         _ => error("Env.get called with non-Str"),
     }
 }
 
 pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
     match key {
-        Sym(ref s) => {
-            env.data.borrow_mut().insert(s.to_string(), val.clone());
+        Sym(s) => {
+            env.data.borrow_mut().insert(s, val.clone());
             Ok(val)
         }
         _ => error("Env.set called with non-Str"),
@@ -81,5 +155,5 @@ pub fn env_set(env: &Env, key: MalVal, val: MalVal) -> MalRet {
 }
 
 pub fn env_sets(env: &Env, key: &str, val: MalVal) {
-    env.data.borrow_mut().insert(key.to_string(), val);
+    env.data.borrow_mut().insert(Symbol::intern(key), val);
 }
@@ -0,0 +1,372 @@
+use std::cell::Cell;
+use std::mem;
+use std::ptr;
+use std::rc::Rc;
+
+use crate::analyze::{analyze, Code, Lambda, Target};
+use crate::env::{check_arity, env_find, env_frame, env_get, env_set, Env};
+use crate::lazy::lazy_seq;
+use crate::symbol::{self, Symbol};
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::{Bool, Hash, List, MalFunc, Nil, Set, Sym, Vector};
+use crate::types::{error, hash_map, hash_set, Compiled, MalArgs, MalErr, MalRet, MalVal};
+
+// The full evaluator from stepA: special forms, macros, try*/catch*
+// and tail calls.  Interpreter and the stepA binary both use it.  Forms
+// are analyzed first (see analyze.rs) and the resulting Code is run.
+
+fn qq_iter(elts: &MalArgs) -> MalVal {
+    let mut acc = list![];
+    for elt in elts.iter().rev() {
+        if let List(v, _) = elt {
+            if v.len() == 2 {
+                if let Sym(symbol::SPLICE_UNQUOTE) = v[0] {
+                    acc = list![Sym(symbol::CONCAT), v[1].clone(), acc];
+                    continue;
+                }
+            }
+        }
+        acc = list![Sym(symbol::CONS), quasiquote(elt), acc];
+    }
+    acc
+}
+
+pub fn quasiquote(ast: &MalVal) -> MalVal {
+    match ast {
+        List(v, _) => {
+            if v.len() == 2 {
+                if let Sym(symbol::UNQUOTE) = v[0] {
+                    return v[1].clone();
+                }
+            }
+            qq_iter(&v.to_vec())
+        }
+        Vector(v, _) => list![Sym(symbol::VEC), qq_iter(&v.iter().cloned().collect())],
+        Hash(_, _) | Set(_, _) | Sym(_) => list![Sym(symbol::QUOTE), ast.clone()],
+        _ => ast.clone(),
+    }
+}
+
+fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
+    match ast {
+        List(v, _) => match v.first() {
+            Some(&Sym(s)) => match env_find(env, s) {
+                Some(e) => match env_get(&e, &Sym(s)) {
+                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v.rest().to_vec())),
+                    _ => None,
+                },
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
+    let mut was_expanded = false;
+    while let Some((mf, args)) = is_macro_call(&ast, env) {
+        //println!("macroexpand 1: {:?}", ast);
+        ast = match mf.apply(args) {
+            Err(e) => return (false, Err(e)),
+            Ok(a) => a,
+        };
+        //println!("macroexpand 2: {:?}", ast);
+        was_expanded = true;
+    }
+    (was_expanded, Ok(ast))
+}
+
+// A level of nesting is a call to a fn* function that isn't a tail call
+// (or a VM frame), or realizing a lazy seq that another one is built on.
+// This is the native stack one level may need, with room to spare on the
+// deepest paths (calls made through map or apply).  Frames are several
+// times bigger in debug builds.
+pub const STACK_PER_DEPTH: usize = if cfg!(debug_assertions) {
+    64 << 10
+} else {
+    8 << 10
+};
+
+// Rather than let deep non-tail recursion overflow the native stack and
+// abort the process, eval counts how deeply it is nested and throws a
+// catchable "stack overflow" past a limit.  Unless set_max_depth says
+// otherwise, the limit is what fits in the stack the thread has left the
+// first time it looks, less some room for the caller and for whatever
+// runs below the last level; run_with_stack gives room for more.
+pub fn default_max_depth() -> usize {
+    let left = stack_left().unwrap_or(1 << 20);
+    left.saturating_sub(256 << 10) / STACK_PER_DEPTH
+}
+
+// how much of this thread's stack is below the current frame
+#[cfg(target_os = "linux")]
+fn stack_left() -> Option<usize> {
+    unsafe {
+        let mut attr: libc::pthread_attr_t = mem::zeroed();
+        if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+            return None;
+        }
+        let (mut low, mut size) = (ptr::null_mut(), 0);
+        let ret = libc::pthread_attr_getstack(&attr, &mut low, &mut size);
+        libc::pthread_attr_destroy(&mut attr);
+        if ret != 0 {
+            return None;
+        }
+        let here = &attr as *const _ as usize;
+        Some(here.saturating_sub(low as usize))
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn stack_left() -> Option<usize> {
+    unsafe {
+        let thread = libc::pthread_self();
+        let low = libc::pthread_get_stackaddr_np(thread) as usize
+            - libc::pthread_get_stacksize_np(thread);
+        let here = &thread as *const _ as usize;
+        Some(here.saturating_sub(low))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn stack_left() -> Option<usize> {
+    None
+}
+
+thread_local! {
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    static MAX_DEPTH: Cell<usize> = Cell::new(default_max_depth());
+}
+
+// how many levels of nesting (see STACK_PER_DEPTH) this thread allows
+pub fn set_max_depth(n: usize) {
+    MAX_DEPTH.with(|m| m.set(n));
+}
+
+// leaves the depth as it was when eval returns, or unwinds from a panic
+pub struct DepthGuard(());
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1));
+    }
+}
+
+// one more level of nesting, until the guard is dropped
+pub fn nest() -> Result<DepthGuard, MalErr> {
+    DEPTH.with(|d| d.set(d.get() + 1));
+    let guard = DepthGuard(());
+    check_depth(0)?;
+    Ok(guard)
+}
+
+// fails if the current depth plus extra levels is past the limit
+pub fn check_depth(extra: usize) -> Result<(), MalErr> {
+    if DEPTH.with(|d| d.get()) + extra > MAX_DEPTH.with(|m| m.get()) {
+        return Err(ErrString("stack overflow".to_string()));
+    }
+    Ok(())
+}
+
+// Analyzes a form and runs it.
+pub fn eval(ast: MalVal, env: Env) -> MalRet {
+    let code = analyze(&top_level(ast, &env, eval)?, &env)?;
+    exec(&code, env)
+}
+
+// A do at the top level is taken apart before it is analyzed: all but
+// its last form are evaluated with eval, so that a macro defined in it
+// can be used by the forms after, and the last is returned.
+pub fn top_level(mut ast: MalVal, env: &Env, eval: fn(MalVal, Env) -> MalRet) -> MalRet {
+    while let Some(forms) = top_level_do(&ast) {
+        let (last, init) = match forms.split_last() {
+            Some(split) => split,
+            None => return Ok(Nil),
+        };
+        for form in init {
+            eval(form.clone(), env.clone())?;
+        }
+        ast = last.clone();
+    }
+    Ok(ast)
+}
+
+fn top_level_do(ast: &MalVal) -> Option<MalArgs> {
+    match ast {
+        List(l, _) if !l.is_empty() && matches!(l[0], Sym(symbol::DO)) => Some(l.rest().to_vec()),
+        _ => None,
+    }
+}
+
+// Runs analyzed code in a frame of its locals (see analyze.rs).
+pub fn exec(code: &Rc<Code>, env: Env) -> MalRet {
+    exec_tco(code.clone(), env)
+}
+
+// calls a function made by fn*, from outside the evaluator
+pub fn call(lambda: &Lambda, env: &Env, args: MalArgs) -> MalRet {
+    let _guard = nest()?;
+    let env = bind(lambda, env, lambda.name, args)?;
+    exec_tco(lambda.body.clone(), env)
+}
+
+// the frame a call runs in: the args, any & rest collected into a list,
+// then room for the def!s in the body
+fn bind(lambda: &Lambda, outer: &Env, name: Symbol, mut args: MalArgs) -> Result<Env, MalErr> {
+    check_arity(name, lambda.arity, args.len())?;
+    if let (min, None) = lambda.arity {
+        let rest = args.split_off(min);
+        args.push(list!(rest));
+    }
+    args.resize(lambda.frame_size, Nil);
+    Ok(env_frame(outer.clone(), args))
+}
+
+fn frame(env: &Env, depth: usize) -> &Env {
+    let mut env = env;
+    for _ in 0..depth {
+        env = env
+            .outer
+            .as_ref()
+            .expect("analyzed code run outside its frames");
+    }
+    env
+}
+
+pub fn root(env: &Env) -> Env {
+    let mut env = env;
+    while let Some(ref outer) = env.outer {
+        env = outer;
+    }
+    env.clone()
+}
+
+fn define(env: &Env, target: &Target, val: MalVal) -> MalRet {
+    match target {
+        Target::Local(slot) => {
+            env.slots.borrow_mut()[*slot] = val.clone();
+            Ok(val)
+        }
+        Target::Global(name) => env_set(env, name.clone(), val),
+    }
+}
+
+fn exec_all(codes: &[Rc<Code>], env: &Env) -> Result<MalArgs, MalErr> {
+    codes.iter().map(|c| exec(c, env.clone())).collect()
+}
+
+// code and env are replaced on each tail call
+fn exec_tco(mut code: Rc<Code>, mut env: Env) -> MalRet {
+    // the first fn* called from here is one level deeper; the calls after
+    // it are tail calls and reuse that level
+    let mut guard = None;
+    loop {
+        let next = match &*code {
+            Code::Const(v) => return Ok(v.clone()),
+            Code::Local(depth, slot) => {
+                return Ok(frame(&env, *depth).slots.borrow()[*slot].clone())
+            }
+            Code::Global(depth, name, span) => {
+                return env_get(frame(&env, *depth), name).map_err(|e| e.at(span.clone()))
+            }
+            Code::Def(target, val) => {
+                let val = exec(val, env.clone())?;
+                return define(&env, target, val);
+            }
+            Code::DefMacro(target, val) => match exec(val, env.clone())? {
+                MalFunc {
+                    eval,
+                    ast,
+                    env: fenv,
+                    params,
+                    code,
+                    ..
+                } => {
+                    let mac = MalFunc {
+                        eval,
+                        ast,
+                        env: fenv,
+                        params,
+                        code,
+                        is_macro: true,
+                        meta: Rc::new(Nil),
+                    };
+                    return define(&env, target, mac);
+                }
+                _ => return error("set_macro on non-function"),
+            },
+            Code::Let(size, inits, body) => {
+                env = env_frame(env, vec![Nil; *size]);
+                for (slot, init) in inits {
+                    let val = exec(init, env.clone())?;
+                    env.slots.borrow_mut()[*slot] = val;
+                }
+                body.clone()
+            }
+            Code::Do(init, last) => {
+                for c in init {
+                    exec(c, env.clone())?;
+                }
+                last.clone()
+            }
+            Code::If(cond, then, otherwise) => match exec(cond, env.clone())? {
+                Bool(false) | Nil => otherwise.clone(),
+                _ => then.clone(),
+            },
+            Code::Fn(lambda, params, body) => {
+                return Ok(MalFunc {
+                    eval,
+                    ast: body.clone(),
+                    env,
+                    params: params.clone(),
+                    code: Some(Compiled::Tree(lambda.clone())),
+                    is_macro: false,
+                    meta: Rc::new(Nil),
+                })
+            }
+            Code::Try(body, handler) => match (exec(body, env.clone()), handler) {
+                (Err(e), Some((size, handler))) => {
+                    let mut slots = vec![e.value()];
+                    slots.resize(*size, Nil);
+                    env = env_frame(env, slots);
+                    handler.clone()
+                }
+                (res, _) => return res,
+            },
+            Code::LazySeq(body) => {
+                let (body, env) = (body.clone(), env.clone());
+                return Ok(lazy_seq(move || exec(&body, env.clone())));
+            }
+            Code::Eval(form) => {
+                let form = exec(form, env.clone())?;
+                env = root(&env);
+                analyze(&top_level(form, &env, eval)?, &env)?
+            }
+            Code::Macroexpand(form) => return macroexpand(form.clone(), &root(&env)).1,
+            Code::Call(call) => {
+                let f = exec(&call.f, env.clone())?;
+                let args = exec_all(&call.args, &env)?;
+                match f {
+                    MalFunc {
+                        code: Some(Compiled::Tree(ref lambda)),
+                        env: ref fenv,
+                        ..
+                    } => {
+                        if guard.is_none() {
+                            guard = Some(nest()?);
+                        }
+                        env = bind(lambda, fenv, call.name, args)
+                            .map_err(|e| e.at(call.span.clone()))?;
+                        lambda.body.clone()
+                    }
+                    _ => return f.apply(args).map_err(|e| e.at(call.span.clone())),
+                }
+            }
+            Code::Vector(items) => return Ok(vector!(exec_all(items, &env)?)),
+            Code::Hash(items) => return hash_map(exec_all(items, &env)?),
+            Code::Set(items) => return Ok(hash_set(exec_all(items, &env)?)),
+        };
+        code = next;
+    }
+}
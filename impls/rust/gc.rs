@@ -0,0 +1,300 @@
+use std::cell::{Cell, RefCell};
+use std::mem;
+use std::rc::{Rc, Weak};
+
+use fnv::FnvHashMap;
+
+use crate::env::{Env, EnvStruct};
+use crate::list::MalList;
+use crate::types::MalVal::{Atom, Func, Hash, List, MalFunc, Nil, Set, Vector};
+use crate::types::{Compiled, MalVal};
+
+// Cycle collector.  Reference counting frees everything except cycles,
+// and only envs and atoms can be mutated to point back at themselves, so
+// every cycle passes through one of them: a def!'d closure and the env
+// it was defined in, or an atom holding (a closure over) itself.  Each
+// env and atom is registered here when it is created, and so is each box
+// the VM keeps a captured local in, which is an atom the program never
+// sees and is left out of the stats.
+//
+// collect() works by trial deletion: for every registered object it
+// subtracts the references it can see coming from other registered
+// objects.  Whatever still has references left is held from outside
+// (the Rust stack, the REPL, a native closure) and is live, along with
+// everything it reaches; the rest is only reachable from garbage, so its
+// contents are cleared to break the cycles and reference counting frees
+// it.
+//
+// A reference is only counted when it is owned outright along the way,
+// since counting one that is shared would make an object look less
+// referenced than it is.  So the walk goes through closures (and the
+// upvalue boxes of compiled ones), atoms and unshared list cells, but not
+// into vectors, maps or sets (whose trie nodes are shared between
+// versions) or lazy seqs and native closures.
+// Cycles through those are kept.
+
+type AtomRef = Rc<RefCell<MalVal>>;
+
+enum Tracked {
+    Env(Weak<EnvStruct>),
+    Atom(Weak<RefCell<MalVal>>),
+    Upval(Weak<RefCell<MalVal>>),
+}
+
+enum Node {
+    Env(Env),
+    Atom(AtomRef),
+    Upval(AtomRef),
+}
+
+// collections run automatically once the number of registered objects
+// has doubled since the last one
+const MIN_HEAP: usize = 10000;
+
+thread_local! {
+    static HEAP: RefCell<Vec<Tracked>> = const { RefCell::new(vec![]) };
+    static NEXT_GC: Cell<usize> = const { Cell::new(MIN_HEAP) };
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct GcStats {
+    // objects still alive after the collection
+    pub envs: usize,
+    pub atoms: usize,
+    // objects in garbage cycles that were freed
+    pub freed_envs: usize,
+    pub freed_atoms: usize,
+}
+
+pub fn track_env(env: &Env) {
+    track(Tracked::Env(Rc::downgrade(env)));
+}
+
+pub fn track_atom(a: &AtomRef) {
+    track(Tracked::Atom(Rc::downgrade(a)));
+}
+
+pub fn track_upval(b: &AtomRef) {
+    track(Tracked::Upval(Rc::downgrade(b)));
+}
+
+fn track(t: Tracked) {
+    let len = HEAP.with(|h| {
+        let mut h = h.borrow_mut();
+        h.push(t);
+        h.len()
+    });
+    if len >= NEXT_GC.with(|n| n.get()) {
+        collect();
+    }
+}
+
+fn addr<T>(rc: &Rc<T>) -> usize {
+    Rc::as_ptr(rc) as *const u8 as usize
+}
+
+impl Tracked {
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Tracked::Env(w) => w.upgrade().map(Node::Env),
+            Tracked::Atom(w) => w.upgrade().map(Node::Atom),
+            Tracked::Upval(w) => w.upgrade().map(Node::Upval),
+        }
+    }
+}
+
+impl Node {
+    fn addr(&self) -> usize {
+        match self {
+            Node::Env(e) => addr(e),
+            Node::Atom(a) | Node::Upval(a) => addr(a),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Env(e) => Rc::strong_count(e),
+            Node::Atom(a) | Node::Upval(a) => Rc::strong_count(a),
+        }
+    }
+
+    fn downgrade(&self) -> Tracked {
+        match self {
+            Node::Env(e) => Tracked::Env(Rc::downgrade(e)),
+            Node::Atom(a) => Tracked::Atom(Rc::downgrade(a)),
+            Node::Upval(a) => Tracked::Upval(Rc::downgrade(a)),
+        }
+    }
+
+    // addresses of the envs and atoms this one references; anything
+    // borrowed mutably right now is left unvisited, which only makes
+    // its children look more referenced
+    fn children(&self, out: &mut Vec<usize>) {
+        match self {
+            Node::Env(e) => {
+                if let Some(ref o) = e.outer {
+                    out.push(addr(o));
+                }
+                if let Ok(data) = e.data.try_borrow() {
+                    for v in data.values() {
+                        trace(v, out);
+                    }
+                }
+                if let Ok(slots) = e.slots.try_borrow() {
+                    for v in slots.iter() {
+                        trace(v, out);
+                    }
+                }
+            }
+            Node::Atom(a) | Node::Upval(a) => {
+                if let Ok(v) = a.try_borrow() {
+                    trace(&v, out);
+                }
+            }
+        }
+    }
+
+    // drop the contents, breaking any cycle through this object
+    fn clear(&self, dropped: &mut Vec<MalVal>) {
+        match self {
+            Node::Env(e) => {
+                if let Ok(mut data) = e.data.try_borrow_mut() {
+                    dropped.extend(mem::take(&mut *data).into_values());
+                }
+                if let Ok(mut slots) = e.slots.try_borrow_mut() {
+                    dropped.append(&mut slots);
+                }
+            }
+            Node::Atom(a) | Node::Upval(a) => {
+                if let Ok(mut v) = a.try_borrow_mut() {
+                    dropped.push(mem::replace(&mut *v, Nil));
+                }
+            }
+        }
+    }
+}
+
+fn trace(v: &MalVal, out: &mut Vec<usize>) {
+    match v {
+        Atom(a) => out.push(addr(a)),
+        MalFunc {
+            env,
+            ast,
+            params,
+            meta,
+            code,
+            ..
+        } => {
+            out.push(addr(env));
+            trace_owned(ast, out);
+            trace_owned(params, out);
+            trace_owned(meta, out);
+            // a compiled closure's upvalues are the VM's boxes
+            if let Some(Compiled::Bytecode(c)) = code {
+                if Rc::strong_count(c) == 1 {
+                    out.extend(c.upvals().iter().map(addr));
+                }
+            }
+        }
+        List(l, meta) => {
+            trace_list(l, out);
+            trace_owned(meta, out);
+        }
+        Vector(_, meta) | Hash(_, meta) | Set(_, meta) | Func(_, meta) => trace_owned(meta, out),
+        _ => (),
+    }
+}
+
+fn trace_owned(v: &Rc<MalVal>, out: &mut Vec<usize>) {
+    if Rc::strong_count(v) == 1 {
+        trace(v, out);
+    }
+}
+
+fn trace_list(l: &MalList, out: &mut Vec<usize>) {
+    for v in l.unshared() {
+        trace(v, out);
+    }
+}
+
+pub fn collect() -> GcStats {
+    // dead entries are dropped here, and the strong references taken
+    // keep everything alive until the end
+    let nodes: Vec<Node> = HEAP.with(|h| {
+        h.borrow_mut()
+            .drain(..)
+            .filter_map(|t| t.upgrade())
+            .collect()
+    });
+    let index: FnvHashMap<usize, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, n)| (n.addr(), i))
+        .collect();
+
+    // references from outside: the count, less the one just taken and
+    // one for each reference from another registered object
+    let mut outside: Vec<isize> = nodes
+        .iter()
+        .map(|n| n.strong_count() as isize - 1)
+        .collect();
+    let mut edges: Vec<Vec<usize>> = Vec::with_capacity(nodes.len());
+    let mut out = vec![];
+    for n in &nodes {
+        out.clear();
+        n.children(&mut out);
+        let e: Vec<usize> = out.iter().filter_map(|a| index.get(a).cloned()).collect();
+        for &j in &e {
+            outside[j] -= 1;
+        }
+        edges.push(e);
+    }
+
+    let mut live = vec![false; nodes.len()];
+    let mut stack: Vec<usize> = (0..nodes.len()).filter(|&i| outside[i] > 0).collect();
+    while let Some(i) = stack.pop() {
+        if !live[i] {
+            live[i] = true;
+            stack.extend(edges[i].iter().filter(|&&j| !live[j]));
+        }
+    }
+
+    let mut dropped = vec![];
+    let mut garbage = vec![];
+    for (n, &l) in nodes.iter().zip(live.iter()) {
+        if !l {
+            n.clear(&mut dropped);
+            garbage.push(n.downgrade());
+        }
+    }
+    let survivors: Vec<Tracked> = nodes.iter().map(Node::downgrade).collect();
+    drop(dropped);
+    drop(nodes);
+
+    let mut stats = GcStats::default();
+    for t in &garbage {
+        match t {
+            Tracked::Env(w) if w.strong_count() == 0 => stats.freed_envs += 1,
+            Tracked::Atom(w) if w.strong_count() == 0 => stats.freed_atoms += 1,
+            _ => (),
+        }
+    }
+    let survivors: Vec<Tracked> = survivors
+        .into_iter()
+        .filter(|t| match t {
+            Tracked::Env(w) => w.strong_count() > 0,
+            Tracked::Atom(w) | Tracked::Upval(w) => w.strong_count() > 0,
+        })
+        .collect();
+    for t in &survivors {
+        match t {
+            Tracked::Env(_) => stats.envs += 1,
+            Tracked::Atom(_) => stats.atoms += 1,
+            Tracked::Upval(_) => (),
+        }
+    }
+    NEXT_GC.with(|n| n.set((2 * survivors.len()).max(MIN_HEAP)));
+    // objects created while collecting were registered in the meantime
+    HEAP.with(|h| h.borrow_mut().extend(survivors));
+    stats
+}
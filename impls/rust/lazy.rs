@@ -0,0 +1,92 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use crate::eval::nest;
+use crate::types::MalErr::ErrString;
+use crate::types::MalVal::LazySeq;
+use crate::types::{MalErr, MalRet, MalVal};
+
+type Cell = Option<(MalVal, MalVal)>;
+
+// A lazy sequence runs its thunk the first time it is looked at and
+// caches the resulting cell: empty, or a first element and the rest of
+// the sequence (often another lazy sequence).
+pub struct Lazy {
+    state: RefCell<State>,
+}
+
+enum State {
+    Thunk(Rc<dyn Fn() -> MalRet>),
+    Realizing,
+    Done(Cell),
+}
+
+impl Lazy {
+    pub fn realize(&self) -> Result<Cell, MalErr> {
+        let thunk = match *self.state.borrow() {
+            State::Done(ref cell) => return Ok(cell.clone()),
+            State::Realizing => return Err(ErrString("lazy-seq depends on itself".to_string())),
+            State::Thunk(ref f) => f.clone(),
+        };
+        // realizing a seq built on another (map over map ...) nests, so it
+        // counts towards the stack overflow limit like a call does
+        let _guard = nest()?;
+        *self.state.borrow_mut() = State::Realizing;
+        match thunk().and_then(|s| s.uncons()) {
+            Ok(cell) => {
+                *self.state.borrow_mut() = State::Done(cell.clone());
+                Ok(cell)
+            }
+            // keep the thunk so the next look retries it
+            Err(e) => {
+                *self.state.borrow_mut() = State::Thunk(thunk);
+                Err(e)
+            }
+        }
+    }
+}
+
+// a realized chain can be arbitrarily long, so unlink it iteratively
+impl Drop for Lazy {
+    fn drop(&mut self) {
+        let mut next = match mem::replace(self.state.get_mut(), State::Realizing) {
+            State::Done(Some((_, rest))) => rest,
+            _ => return,
+        };
+        while let LazySeq(l) = next {
+            next = match Rc::try_unwrap(l) {
+                Ok(mut l) => match mem::replace(l.state.get_mut(), State::Realizing) {
+                    State::Done(Some((_, rest))) => rest,
+                    _ => break,
+                },
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl fmt::Debug for Lazy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self.state.borrow() {
+            State::Done(_) => write!(f, "Lazy(realized)"),
+            _ => write!(f, "Lazy(pending)"),
+        }
+    }
+}
+
+// thunk returns any sequence value (list, vector, nil or lazy seq)
+pub fn lazy_seq<F: Fn() -> MalRet + 'static>(thunk: F) -> MalVal {
+    LazySeq(Rc::new(Lazy {
+        state: RefCell::new(State::Thunk(Rc::new(thunk))),
+    }))
+}
+
+// an already realized cell, used to put an element in front of a
+// sequence without looking at it
+pub fn lazy_cons(first: MalVal, rest: MalVal) -> MalVal {
+    LazySeq(Rc::new(Lazy {
+        state: RefCell::new(State::Done(Some((first, rest)))),
+    }))
+}
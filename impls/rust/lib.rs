@@ -0,0 +1,257 @@
+//! mal (Make a Lisp) as a library.
+//!
+//! The step binaries are the reference REPLs; this crate exposes the same
+//! reader, printer, core functions and stepA evaluator so a Rust program
+//! can embed an interpreter.
+//!
+//! Calling mal from Rust:
+//!
+//! ```
+//! use mal::types::MalVal::Int;
+//! use mal::Interpreter;
+//!
+//! let mal = Interpreter::new();
+//! mal.eval_str("(def! inc (fn* (x) (+ x 1)))").unwrap();
+//! assert_eq!(mal.call("inc", vec![Int(41)]).unwrap(), Int(42));
+//! assert!(mal.get("inc").is_some());
+//! ```
+//!
+//! Calling Rust from mal, with a closure that keeps its own state:
+//!
+//! ```
+//! use std::cell::Cell;
+//! use mal::types::MalVal::Int;
+//! use mal::types::{func, MalVal};
+//! use mal::Interpreter;
+//!
+//! let mal = Interpreter::new();
+//! let calls = Cell::new(0);
+//! mal.define("tick", func("tick", move |_: &[MalVal]| {
+//!     calls.set(calls.get() + 1);
+//!     Ok(Int(calls.get()))
+//! }));
+//! let v = mal.eval_str("(do (tick) (tick) (tick))").unwrap();
+//! assert_eq!(v.pr_str(true), "3");
+//! ```
+//!
+//! Compiling to bytecode and running on the stack VM instead of the
+//! tree evaluator:
+//!
+//! ```
+//! use mal::Interpreter;
+//!
+//! let mal = Interpreter::with_eval(mal::vm::eval);
+//! mal.eval_str("(def! sum (fn* (n acc) (if (= n 0) acc (sum (- n 1) (+ acc n)))))").unwrap();
+//! assert_eq!(mal.eval_str("(sum 100000 0)").unwrap().pr_str(true), "5000050000");
+//! ```
+//!
+//! Passing Rust data in and out through serde, with struct fields as
+//! keywords:
+//!
+//! ```
+//! #[macro_use]
+//! extern crate serde;
+//! extern crate mal;
+//!
+//! use mal::types::{from_mal, to_mal};
+//! use mal::Interpreter;
+//!
+//! #[derive(Serialize, Deserialize, Debug, PartialEq)]
+//! struct Point {
+//!     x: i64,
+//!     y: i64,
+//! }
+//!
+//! fn main() {
+//!     let mal = Interpreter::new();
+//!     mal.define("p", to_mal(&Point { x: 1, y: 2 }).unwrap());
+//!     let moved = mal.eval_str("(assoc p :x (+ (get p :x) 10))").unwrap();
+//!     assert_eq!(from_mal::<Point>(&moved).unwrap(), Point { x: 11, y: 2 });
+//!
+//!     let bad = mal.eval_str("[{:x 1 :y 2} {:x 3 :y \"4\"}]").unwrap();
+//!     let err = from_mal::<Vec<Point>>(&bad).unwrap_err();
+//!     assert_eq!(
+//!         err.to_string(),
+//!         "[1].y: invalid type: string \"4\", expected i64"
+//!     );
+//! }
+//! ```
+
+extern crate fnv;
+extern crate im_rc;
+extern crate itertools;
+extern crate libc;
+extern crate num_bigint;
+extern crate num_rational;
+extern crate num_traits;
+extern crate rustyline;
+extern crate serde;
+
+#[macro_use]
+pub mod types;
+pub mod analyze;
+pub mod core;
+pub mod env;
+pub mod eval;
+pub mod gc;
+pub mod lazy;
+pub mod list;
+pub mod printer;
+pub mod reader;
+pub mod symbol;
+pub mod vm;
+
+use std::panic;
+use std::thread;
+
+use crate::env::{env_find, env_get, env_new, env_sets, Env};
+use crate::symbol::Symbol;
+use crate::types::MalVal::{Nil, Sym};
+use crate::types::{MalRet, MalVal};
+
+// core.mal: defined using the language itself
+const PRELUDE: &str = r#"
+(def! *host-language* "rust")
+(def! not (fn* (a) (if a false true)))
+(def! load-file (fn* (f) (do (map (fn* (form) (eval form)) (read-string-all (slurp f) f)) nil)))
+(defmacro! cond (fn* (& xs) (if (> (count xs) 0) (list 'if (first xs) (if (> (count xs) 1) (nth xs 1) (throw "odd number of forms to cond")) (cons 'cond (rest (rest xs)))))))
+"#;
+
+/// A mal interpreter with its own global environment, holding the core
+/// functions and the stepA prelude.
+///
+/// Recursion deeper than the thread's limit throws a "stack overflow" mal
+/// exception. The default limit, `eval::default_max_depth`, is what fits in
+/// the stack of the thread the interpreter runs on; use `run_with_stack`
+/// to recurse deeper than that.
+///
+/// ```
+/// use std::thread;
+///
+/// let recurse = |n| {
+///     let mal = mal::Interpreter::new();
+///     mal.eval_str("(def! f (fn* (n) (if (= n 0) 0 (+ 1 (f (- n 1))))))").unwrap();
+///     mal.eval_str(&format!("(f {})", n)).map(|v| v.pr_str(true))
+/// };
+/// assert_eq!(recurse(50).unwrap(), "50");
+/// let deep = thread::Builder::new().stack_size(512 << 20);
+/// let n = deep.spawn(move || recurse(5000).unwrap()).unwrap().join().unwrap();
+/// assert_eq!(n, "5000");
+/// // too deep for the thread's stack
+/// assert!(recurse(1 << 20).is_err());
+/// ```
+pub struct Interpreter {
+    env: Env,
+    eval: fn(MalVal, Env) -> MalRet,
+}
+
+impl Interpreter {
+    pub fn new() -> Interpreter {
+        Interpreter::with_eval(eval::eval)
+    }
+
+    /// An interpreter that evaluates with `eval` rather than the tree
+    /// evaluator, e.g. `vm::eval` to compile to bytecode.
+    pub fn with_eval(eval: fn(MalVal, Env) -> MalRet) -> Interpreter {
+        let env = env_new(None);
+        for (k, v) in core::ns() {
+            env_sets(&env, k, v);
+        }
+        env_sets(&env, "*ARGV*", list![]);
+        let interp = Interpreter { env, eval };
+        if let Err(e) = interp.eval_str(PRELUDE) {
+            panic!("mal prelude failed: {}", types::format_error(e));
+        }
+        interp
+    }
+
+    /// Reads and evaluates every form in `src`, returning the value of
+    /// the last one (nil if there are none). A panic while evaluating is
+    /// returned as an error, and one in a builtin can be caught by try*.
+    ///
+    /// ```
+    /// let boom = mal::types::func("boom", |_| panic!("boom"));
+    /// for &eval in &[mal::eval::eval as fn(_, _) -> _, mal::vm::eval] {
+    ///     let mal = mal::Interpreter::with_eval(eval);
+    ///     mal.define("boom", boom.clone());
+    ///     let e = mal.eval_str("(boom)").unwrap_err();
+    ///     assert!(e.to_string().ends_with("panic: boom"));
+    ///     let v = mal.eval_str("(try* (boom) (catch* e e))").unwrap();
+    ///     assert_eq!(v.pr_str(true), "\"panic: boom\"");
+    /// }
+    /// ```
+    pub fn eval_str(&self, src: &str) -> MalRet {
+        let mut ret = Nil;
+        for form in reader::read_all(src) {
+            ret = self.eval(form?)?;
+        }
+        Ok(ret)
+    }
+
+    /// Evaluates a form that was already read, returning a panic as an
+    /// error.
+    pub fn eval(&self, ast: MalVal) -> MalRet {
+        types::catch_panic(|| (self.eval)(ast, self.env.clone()))
+    }
+
+    /// Binds `name` in the global environment.
+    pub fn define(&self, name: &str, val: MalVal) {
+        env_sets(&self.env, name, val);
+    }
+
+    /// Looks `name` up in the global environment.
+    pub fn get(&self, name: &str) -> Option<MalVal> {
+        let sym = Symbol::intern(name);
+        env_find(&self.env, sym).and_then(|e| env_get(&e, &Sym(sym)).ok())
+    }
+
+    /// Calls the function bound to `name` with already evaluated args.
+    pub fn call(&self, name: &str, args: Vec<MalVal>) -> MalRet {
+        env_get(&self.env, &Sym(Symbol::intern(name)))?.apply(args)
+    }
+
+    /// The global environment, for evaluating forms that were read
+    /// separately.
+    pub fn env(&self) -> &Env {
+        &self.env
+    }
+}
+
+/// Runs `f` on a new thread with enough stack for `max_depth` levels of
+/// nesting, and that depth limit set, and returns its result. A level is a
+/// call to a fn* function that isn't a tail call, so `max_depth` is how
+/// deep mal functions can recurse. The interpreter has to be created
+/// inside `f` as it can't change threads.
+///
+/// ```
+/// let n = mal::run_with_stack(20000, || {
+///     let mal = mal::Interpreter::new();
+///     mal.eval_str("(def! f (fn* (n) (if (= n 0) 0 (+ 1 (f (- n 1))))))").unwrap();
+///     mal.eval_str("(f 15000)").unwrap().pr_str(true)
+/// });
+/// assert_eq!(n, "15000");
+/// ```
+pub fn run_with_stack<F, T>(max_depth: usize, f: F) -> T
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    let stack = max_depth.saturating_mul(eval::STACK_PER_DEPTH) + (1 << 20);
+    let handle = thread::Builder::new()
+        .stack_size(stack)
+        .spawn(move || {
+            eval::set_max_depth(max_depth);
+            f()
+        })
+        .expect("can't spawn the interpreter thread");
+    match handle.join() {
+        Ok(ret) => ret,
+        Err(p) => panic::resume_unwind(p),
+    }
+}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
@@ -0,0 +1,163 @@
+use std::any::Any;
+use std::iter::FromIterator;
+use std::ops::Index;
+use std::rc::{Rc, Weak};
+
+use crate::types::MalVal;
+
+// Persistent singly-linked list. cons and rest are O(1) and share the
+// tail with the original list; indexing walks from the front.
+#[derive(Debug, Clone, Default)]
+pub struct MalList {
+    head: Option<Rc<Node>>,
+    len: usize,
+}
+
+#[derive(Debug)]
+struct Node {
+    val: MalVal,
+    next: Option<Rc<Node>>,
+}
+
+impl MalList {
+    pub fn new() -> MalList {
+        MalList::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn first(&self) -> Option<&MalVal> {
+        self.head.as_ref().map(|n| &n.val)
+    }
+
+    pub fn last(&self) -> Option<&MalVal> {
+        self.iter().last()
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&MalVal> {
+        self.iter().nth(idx)
+    }
+
+    pub fn cons(&self, val: MalVal) -> MalList {
+        MalList {
+            head: Some(Rc::new(Node {
+                val,
+                next: self.head.clone(),
+            })),
+            len: self.len + 1,
+        }
+    }
+
+    // the empty list's rest is itself, as in (rest ())
+    pub fn rest(&self) -> MalList {
+        match self.head {
+            Some(ref n) => MalList {
+                head: n.next.clone(),
+                len: self.len - 1,
+            },
+            None => MalList::new(),
+        }
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter {
+            next: self.head.as_deref(),
+            remaining: self.len,
+        }
+    }
+
+    // the values in the leading cells that no other list shares
+    pub fn unshared(&self) -> Vec<&MalVal> {
+        let mut vals = vec![];
+        let mut next = self.head.as_ref();
+        while let Some(n) = next {
+            if Rc::strong_count(n) > 1 {
+                break;
+            }
+            vals.push(&n.val);
+            next = n.next.as_ref();
+        }
+        vals
+    }
+
+    pub fn to_vec(&self) -> Vec<MalVal> {
+        self.iter().cloned().collect()
+    }
+
+    // identity of the first cell, for side tables keyed by list
+    pub fn downgrade(&self) -> Option<Weak<dyn Any>> {
+        self.head.as_ref().map(|n| {
+            let w: Weak<dyn Any> = Rc::downgrade(n) as Weak<Node>;
+            w
+        })
+    }
+}
+
+// unlink cells one at a time so long lists don't overflow the stack
+impl Drop for MalList {
+    fn drop(&mut self) {
+        let mut next = self.head.take();
+        while let Some(n) = next {
+            match Rc::try_unwrap(n) {
+                Ok(mut n) => next = n.next.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl Index<usize> for MalList {
+    type Output = MalVal;
+
+    fn index(&self, idx: usize) -> &MalVal {
+        match self.get(idx) {
+            Some(v) => v,
+            None => panic!("index {} out of range for list of {}", idx, self.len),
+        }
+    }
+}
+
+impl From<Vec<MalVal>> for MalList {
+    fn from(v: Vec<MalVal>) -> MalList {
+        let mut l = MalList::new();
+        for val in v.into_iter().rev() {
+            l = l.cons(val);
+        }
+        l
+    }
+}
+
+impl FromIterator<MalVal> for MalList {
+    fn from_iter<I: IntoIterator<Item = MalVal>>(iter: I) -> MalList {
+        MalList::from(iter.into_iter().collect::<Vec<MalVal>>())
+    }
+}
+
+pub struct Iter<'a> {
+    next: Option<&'a Node>,
+    remaining: usize,
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a MalVal;
+
+    fn next(&mut self) -> Option<&'a MalVal> {
+        self.next.map(|n| {
+            self.next = n.next.as_deref();
+            self.remaining -= 1;
+            &n.val
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a> ExactSizeIterator for Iter<'a> {}
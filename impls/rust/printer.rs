@@ -1,5 +1,9 @@
 use crate::types::MalVal;
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Ratio, Set,
+    Str, Sym, Vector,
+};
+use crate::types::{format_error, MalErr};
 
 fn escape_str(s: &str) -> String {
     s.chars()
@@ -14,48 +18,67 @@ fn escape_str(s: &str) -> String {
 }
 
 impl MalVal {
+    // For error messages and debugging: a lazy seq that throws while it
+    // is realized is shown as the error instead.
     pub fn pr_str(&self, print_readably: bool) -> String {
-        match self {
+        self.try_pr_str(print_readably)
+            .unwrap_or_else(|e| format!("#<lazy-seq error: {}>", format_error(e)))
+    }
+
+    // Fails with the error thrown realizing a lazy seq inside the value.
+    pub fn try_pr_str(&self, print_readably: bool) -> Result<String, MalErr> {
+        Ok(match self {
             Nil => String::from("nil"),
             Bool(true) => String::from("true"),
             Bool(false) => String::from("false"),
             Int(i) => format!("{}", i),
-            //Float(f)    => format!("{}", f),
+            BigInt(i) if print_readably => format!("{}N", i),
+            BigInt(i) => format!("{}", i),
+            Ratio(r) => format!("{}", r),
+            // Debug keeps the decimal point on whole numbers (1.0, not 1)
+            Float(f) => format!("{:?}", f),
             Str(s) => {
-                if s.starts_with("\u{29e}") {
-                    format!(":{}", &s[2..])
-                } else if print_readably {
+                if print_readably {
                     format!("\"{}\"", escape_str(s))
                 } else {
                     s.clone()
                 }
             }
-            Sym(s) => s.clone(),
-            List(l, _) => pr_seq(&**l, print_readably, "(", ")", " "),
-            Vector(l, _) => pr_seq(&**l, print_readably, "[", "]", " "),
+            Keyword(k) => format!(":{}", k),
+            Sym(s) => s.to_string(),
+            List(l, _) => pr_seq(l.iter(), print_readably, "(", ")", " ")?,
+            Vector(v, _) => pr_seq(v.iter(), print_readably, "[", "]", " ")?,
+            LazySeq(_) => {
+                let l = self.walk().collect::<Result<Vec<MalVal>, MalErr>>()?;
+                pr_seq(&l, print_readably, "(", ")", " ")?
+            }
             Hash(hm, _) => {
                 let l: Vec<MalVal> = hm
                     .iter()
-                    .flat_map(|(k, v)| vec![Str(k.to_string()), v.clone()])
+                    .flat_map(|(k, v)| vec![k.clone(), v.clone()])
                     .collect();
-                pr_seq(&l, print_readably, "{", "}", " ")
+                pr_seq(&l, print_readably, "{", "}", " ")?
             }
-            Func(f, _) => format!("#<fn {:?}>", f),
+            Set(s, _) => pr_seq(s.iter(), print_readably, "#{", "}", " ")?,
+            Func(f, _) => format!("#<fn {}>", f.name),
             MalFunc {
                 ast: a, params: p, ..
-            } => format!("(fn* {} {})", p.pr_str(true), a.pr_str(true)),
-            Atom(a) => format!("(atom {})", a.borrow().pr_str(true)),
-        }
+            } => format!("(fn* {} {})", p.try_pr_str(true)?, a.try_pr_str(true)?),
+            Atom(a) => format!("(atom {})", a.borrow().try_pr_str(true)?),
+        })
     }
 }
 
-pub fn pr_seq(
-    seq: &Vec<MalVal>,
+pub fn pr_seq<'a, I: IntoIterator<Item = &'a MalVal>>(
+    seq: I,
     print_readably: bool,
     start: &str,
     end: &str,
     join: &str,
-) -> String {
-    let strs: Vec<String> = seq.iter().map(|x| x.pr_str(print_readably)).collect();
-    format!("{}{}{}", start, strs.join(join), end)
+) -> Result<String, MalErr> {
+    let strs = seq
+        .into_iter()
+        .map(|x| x.try_pr_str(print_readably))
+        .collect::<Result<Vec<String>, MalErr>>()?;
+    Ok(format!("{}{}{}", start, strs.join(join), end))
 }
@@ -1,156 +1,499 @@
-use regex::{Captures, Regex};
-use std::rc::Rc;
+use std::any::Any;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+//use std::collections::HashMap;
+use fnv::FnvHashMap;
+use num_bigint;
+use num_rational::BigRational;
 
+use crate::symbol::{self, Symbol};
 use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Bool, Int, List, Nil, Str, Sym, Vector};
-use crate::types::{error, hash_map, MalErr, MalRet, MalVal};
+use crate::types::MalVal::{Bool, Float, Hash, Int, List, Nil, Set, Str, Sym, Vector};
+use crate::types::{
+    error, hash_map, hash_set, integer, keyword, ratio, MalErr, MalMap, MalRet, MalSet, MalVal,
+    MalVec, Span,
+};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TokenKind {
+    Delim, // ( ) [ ] { } #{ ' ` ~ ~@ ^ @
+    Str,
+    Number,
+    Symbol,
+    Keyword,
+    Comment,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Token<'a> {
+    kind: TokenKind,
+    text: &'a str,
+    line: usize,
+    col: usize,
+}
+
+// Produces tokens on demand as slices of the source, tracking the
+// 1-based line and column (in chars) of each one.
 #[derive(Debug, Clone)]
-struct Reader {
-    tokens: Vec<String>,
+struct Lexer<'a> {
+    src: &'a str,
     pos: usize,
+    line: usize,
+    col: usize,
+    file: Option<Rc<String>>,
 }
 
-impl Reader {
-    fn next(&mut self) -> Result<String, MalErr> {
-        self.pos = self.pos + 1;
-        Ok(self
-            .tokens
-            .get(self.pos - 1)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+impl<'a> Lexer<'a> {
+    fn new(src: &'a str, file: Option<Rc<String>>) -> Lexer<'a> {
+        Lexer {
+            src,
+            pos: 0,
+            line: 1,
+            col: 1,
+            file,
+        }
+    }
+
+    fn peek_char(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek_char()?;
+        self.pos += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.col = 1;
+        } else {
+            self.col += 1;
+        }
+        Some(c)
+    }
+
+    fn span(&self, line: usize, col: usize) -> Option<Span> {
+        let file = self.file.as_ref()?;
+        Some(Span {
+            file: file.clone(),
+            line,
+            col,
+        })
+    }
+
+    fn error(&self, msg: String, line: usize, col: usize) -> MalErr {
+        match self.span(line, col) {
+            Some(span) => ErrString(msg).at(Some(span)),
+            None => ErrString(format!("{} at {}:{}", msg, line, col)),
+        }
     }
-    fn peek(&self) -> Result<String, MalErr> {
-        Ok(self
-            .tokens
-            .get(self.pos)
-            .ok_or(ErrString("underflow".to_string()))?
-            .to_string())
+
+    // consumes the rest of a string literal, validating escapes
+    fn lex_string(&mut self, line: usize, col: usize) -> Result<(), MalErr> {
+        loop {
+            let (esc_line, esc_col) = (self.line, self.col);
+            match self.bump() {
+                Some('"') => return Ok(()),
+                Some('\\') => match self.bump() {
+                    Some('n') | Some('"') | Some('\\') => (),
+                    Some(c) => {
+                        return Err(self.error(
+                            format!("invalid escape '\\{}' in string", c),
+                            esc_line,
+                            esc_col,
+                        ))
+                    }
+                    None => break,
+                },
+                Some(_) => (),
+                None => break,
+            }
+        }
+        Err(self.error(
+            "unterminated string, expected '\"', got EOF".to_string(),
+            line,
+            col,
+        ))
     }
 }
 
-fn tokenize(str: &str) -> Vec<String> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(
-            r###"[\s,]*(~@|[\[\]{}()'`~^@]|"(?:\\.|[^\\"])*"?|;.*|[^\s\[\]{}('"`,;)]+)"###
-        )
-        .unwrap();
+fn is_delimiter(c: char) -> bool {
+    c.is_whitespace() || "[]{}()'\"`,;".contains(c)
+}
+
+// -?[0-9]+N, -?[0-9]+/[0-9]+ or -?[0-9]+(\.[0-9]*)?([eE][-+]?[0-9]+)?
+fn is_number(text: &str) -> bool {
+    let s = text.strip_prefix('-').unwrap_or(text).as_bytes();
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while *i < s.len() && s[*i].is_ascii_digit() {
+            *i += 1;
+        }
+        *i > start
+    };
+    let mut i = 0;
+    if !digits(&mut i) {
+        return false;
+    }
+    if i + 1 == s.len() && s[i] == b'N' {
+        return true;
+    }
+    if i < s.len() && s[i] == b'/' {
+        i += 1;
+        return digits(&mut i) && i == s.len();
     }
+    if i < s.len() && s[i] == b'.' {
+        i += 1;
+        digits(&mut i);
+    }
+    if i < s.len() && (s[i] == b'e' || s[i] == b'E') {
+        i += 1;
+        if i < s.len() && (s[i] == b'+' || s[i] == b'-') {
+            i += 1;
+        }
+        if !digits(&mut i) {
+            return false;
+        }
+    }
+    i == s.len()
+}
 
-    let mut res = vec![];
-    for cap in RE.captures_iter(str) {
-        if cap[1].starts_with(";") {
-            continue;
+fn atom_kind(text: &str) -> TokenKind {
+    if is_number(text) {
+        TokenKind::Number
+    } else if text.starts_with(':') {
+        TokenKind::Keyword
+    } else {
+        TokenKind::Symbol
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>, MalErr>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(c) = self.peek_char() {
+            if !(c.is_whitespace() || c == ',') {
+                break;
+            }
+            self.bump();
         }
-        res.push(String::from(&cap[1]));
+        let (start, line, col) = (self.pos, self.line, self.col);
+        let kind = match self.bump()? {
+            '(' | ')' | '[' | ']' | '{' | '}' | '\'' | '`' | '^' | '@' => TokenKind::Delim,
+            '#' if self.peek_char() == Some('{') => {
+                self.bump();
+                TokenKind::Delim
+            }
+            '~' => {
+                if self.peek_char() == Some('@') {
+                    self.bump();
+                }
+                TokenKind::Delim
+            }
+            '"' => {
+                if let Err(e) = self.lex_string(line, col) {
+                    return Some(Err(e));
+                }
+                TokenKind::Str
+            }
+            ';' => {
+                while self.peek_char().is_some_and(|c| c != '\n') {
+                    self.bump();
+                }
+                TokenKind::Comment
+            }
+            _ => {
+                while self.peek_char().is_some_and(|c| !is_delimiter(c)) {
+                    self.bump();
+                }
+                atom_kind(&self.src[start..self.pos])
+            }
+        };
+        Some(Ok(Token {
+            kind,
+            text: &self.src[start..self.pos],
+            line,
+            col,
+        }))
     }
-    res
 }
 
-fn unescape_str(s: &str) -> String {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r#"\\(.)"#).unwrap();
+#[derive(Debug, Clone)]
+struct Reader<'a> {
+    lexer: Lexer<'a>,
+    peeked: Option<Token<'a>>,
+}
+
+impl<'a> Reader<'a> {
+    fn next(&mut self) -> Result<Token<'a>, MalErr> {
+        let token = self.peek()?;
+        self.peeked = None;
+        token.ok_or(ErrString("underflow".to_string()))
+    }
+    fn peek(&mut self) -> Result<Option<Token<'a>>, MalErr> {
+        if self.peeked.is_none() {
+            self.peeked = self
+                .lexer
+                .find(|t| !matches!(t, Ok(t) if t.kind == TokenKind::Comment))
+                .transpose()?;
+        }
+        Ok(self.peeked)
+    }
+    fn span(&self, token: &Token) -> Option<Span> {
+        self.lexer.span(token.line, token.col)
+    }
+    fn error(&self, msg: String, token: &Token) -> MalErr {
+        self.lexer.error(msg, token.line, token.col)
     }
-    RE.replace_all(&s, |caps: &Captures| {
-        format!("{}", if &caps[1] == "n" { "\n" } else { &caps[1] })
-    })
-    .to_string()
 }
 
-fn read_atom(rdr: &mut Reader) -> MalRet {
-    lazy_static! {
-        static ref INT_RE: Regex = Regex::new(r"^-?[0-9]+$").unwrap();
-        static ref STR_RE: Regex = Regex::new(r#""(?:\\.|[^\\"])*""#).unwrap();
+// Spans are kept in a side table keyed by the address of the
+// collection's shared storage, so they survive clones of the MalVal
+// without showing up in meta.  The Weak reference keeps that address
+// from being reused while the entry exists.
+struct SpanEntry {
+    owner: Weak<dyn Any>,
+    span: Span,
+    elems: Vec<Span>,
+}
+
+#[derive(Default)]
+struct SpanTable {
+    entries: FnvHashMap<usize, SpanEntry>,
+    limit: usize,
+}
+
+thread_local! {
+    static SPANS: RefCell<SpanTable> = RefCell::new(SpanTable::default());
+}
+
+fn span_key(form: &MalVal) -> Option<(usize, Weak<dyn Any>)> {
+    match form {
+        List(l, _) => {
+            let w = l.downgrade()?;
+            Some((w.as_ptr() as *const u8 as usize, w))
+        }
+        Vector(v, _) => {
+            let w: Weak<dyn Any> = Rc::downgrade(v) as Weak<MalVec>;
+            Some((Rc::as_ptr(v) as usize, w))
+        }
+        Hash(hm, _) => {
+            let w: Weak<dyn Any> = Rc::downgrade(hm) as Weak<MalMap>;
+            Some((Rc::as_ptr(hm) as usize, w))
+        }
+        Set(s, _) => {
+            let w: Weak<dyn Any> = Rc::downgrade(s) as Weak<MalSet>;
+            Some((Rc::as_ptr(s) as usize, w))
+        }
+        _ => None,
     }
-    let token = rdr.next()?;
-    match &token[..] {
-        "nil" => Ok(Nil),
-        "false" => Ok(Bool(false)),
-        "true" => Ok(Bool(true)),
-        _ => {
-            if INT_RE.is_match(&token) {
-                Ok(Int(token.parse().unwrap()))
-            } else if STR_RE.is_match(&token) {
-                Ok(Str(unescape_str(&token[1..token.len() - 1])))
-            } else if token.starts_with("\"") {
-                error("expected '\"', got EOF")
-            } else if token.starts_with(":") {
-                Ok(Str(format!("\u{29e}{}", &token[1..])))
-            } else {
-                Ok(Sym(token.to_string()))
+}
+
+fn record_span(form: MalVal, span: Option<Span>, elems: Vec<Span>) -> MalVal {
+    if let (Some(span), Some((key, owner))) = (span, span_key(&form)) {
+        SPANS.with(|t| {
+            let mut t = t.borrow_mut();
+            if t.entries.len() >= t.limit {
+                t.entries.retain(|_, e| e.owner.strong_count() > 0);
+                t.limit = std::cmp::max(1024, 2 * t.entries.len());
             }
+            t.entries.insert(key, SpanEntry { owner, span, elems });
+        });
+    }
+    form
+}
+
+// position of a list, vector or hash-map read from a file
+pub fn form_span(form: &MalVal) -> Option<Span> {
+    let (key, _) = span_key(form)?;
+    SPANS.with(|t| t.borrow().entries.get(&key).map(|e| e.span.clone()))
+}
+
+// position of the idx'th element of a list or vector read from a file
+pub fn elem_span(form: &MalVal, idx: usize) -> Option<Span> {
+    let (key, _) = span_key(form)?;
+    SPANS.with(|t| {
+        t.borrow()
+            .entries
+            .get(&key)
+            .and_then(|e| e.elems.get(idx).cloned())
+    })
+}
+
+fn unescape_str(s: &str) -> String {
+    let mut res = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some('n') => res.push('\n'),
+                Some(c) => res.push(c),
+                None => (),
+            },
+            _ => res.push(c),
+        }
+    }
+    res
+}
+
+// integer literals too big for an i64 become BigInts (an N suffix, as
+// printed, is allowed on any of them), and ratios are reduced to lowest
+// terms
+fn read_number(text: &str) -> Option<MalVal> {
+    if let Some((n, d)) = text.split_once('/') {
+        let (n, d) = (n.parse().ok()?, d.parse().ok()?);
+        if d == num_bigint::BigInt::from(0) {
+            return None;
+        }
+        Some(ratio(BigRational::new(n, d)))
+    } else if let Some(digits) = text.strip_suffix('N') {
+        digits.parse().ok().map(integer)
+    } else if text.contains(['.', 'e', 'E']) {
+        text.parse().ok().map(Float)
+    } else {
+        match text.parse() {
+            Ok(i) => Some(Int(i)),
+            Err(_) => text.parse().ok().map(integer),
         }
     }
 }
 
+fn read_atom(rdr: &mut Reader) -> MalRet {
+    let token = rdr.next()?;
+    match token.kind {
+        TokenKind::Number => match read_number(token.text) {
+            Some(n) => Ok(n),
+            None => Err(rdr.error(format!("invalid number: {}", token.text), &token)),
+        },
+        TokenKind::Str => Ok(Str(unescape_str(&token.text[1..token.text.len() - 1]))),
+        TokenKind::Keyword => Ok(keyword(&token.text[1..])),
+        _ => match token.text {
+            "nil" => Ok(Nil),
+            "false" => Ok(Bool(false)),
+            "true" => Ok(Bool(true)),
+            _ => Ok(Sym(Symbol::intern(token.text))),
+        },
+    }
+}
+
 fn read_seq(rdr: &mut Reader, end: &str) -> MalRet {
     let mut seq: Vec<MalVal> = vec![];
-    rdr.next()?;
+    let mut elems: Vec<Span> = vec![];
+    let open = rdr.next()?;
+    let span = rdr.span(&open);
     loop {
-        let token = match rdr.peek() {
-            Ok(t) => t,
-            Err(_) => return error(&format!("expected '{}', got EOF", end)),
+        // an unclosed list is reported where it was opened
+        let token = match rdr.peek()? {
+            Some(t) => t,
+            None => return Err(rdr.error(format!("expected '{}', got EOF", end), &open)),
         };
-        if token == end {
+        if token.text == end {
             break;
         }
+        elems.extend(rdr.span(&token));
         seq.push(read_form(rdr)?)
     }
     let _ = rdr.next();
-    match end {
-        ")" => Ok(list!(seq)),
-        "]" => Ok(vector!(seq)),
-        "}" => hash_map(seq),
-        _ => error("read_seq unknown end value"),
-    }
+    let form = match open.text {
+        "(" => list!(seq),
+        "[" => vector!(seq),
+        "{" => hash_map(seq)?,
+        "#{" => hash_set(seq),
+        _ => return error("read_seq unknown end value"),
+    };
+    Ok(record_span(form, span, elems))
 }
 
 fn read_form(rdr: &mut Reader) -> MalRet {
-    let token = rdr.peek()?;
-    match &token[..] {
+    let token = rdr.peek()?.ok_or(ErrString("underflow".to_string()))?;
+    let span = rdr.span(&token);
+    let form = match token.text {
         "'" => {
             let _ = rdr.next();
-            Ok(list![Sym("quote".to_string()), read_form(rdr)?])
+            list![Sym(symbol::QUOTE), read_form(rdr)?]
         }
         "`" => {
             let _ = rdr.next();
-            Ok(list![Sym("quasiquote".to_string()), read_form(rdr)?])
+            list![Sym(symbol::QUASIQUOTE), read_form(rdr)?]
         }
         "~" => {
             let _ = rdr.next();
-            Ok(list![Sym("unquote".to_string()), read_form(rdr)?])
+            list![Sym(symbol::UNQUOTE), read_form(rdr)?]
         }
         "~@" => {
             let _ = rdr.next();
-            Ok(list![Sym("splice-unquote".to_string()), read_form(rdr)?])
+            list![Sym(symbol::SPLICE_UNQUOTE), read_form(rdr)?]
         }
         "^" => {
             let _ = rdr.next();
             let meta = read_form(rdr)?;
-            Ok(list![Sym("with-meta".to_string()), read_form(rdr)?, meta])
+            list![Sym(symbol::WITH_META), read_form(rdr)?, meta]
         }
         "@" => {
             let _ = rdr.next();
-            Ok(list![Sym("deref".to_string()), read_form(rdr)?])
+            list![Sym(symbol::DEREF), read_form(rdr)?]
         }
-        ")" => error("unexpected ')'"),
-        "(" => read_seq(rdr, ")"),
-        "]" => error("unexpected ']'"),
-        "[" => read_seq(rdr, "]"),
-        "}" => error("unexpected '}'"),
-        "{" => read_seq(rdr, "}"),
-        _ => read_atom(rdr),
+        ")" | "]" | "}" => return Err(rdr.error(format!("unexpected '{}'", token.text), &token)),
+        "(" => return read_seq(rdr, ")"),
+        "[" => return read_seq(rdr, "]"),
+        "{" => return read_seq(rdr, "}"),
+        "#{" => return read_seq(rdr, "}"),
+        _ => return read_atom(rdr),
+    };
+    Ok(record_span(form, span, vec![]))
+}
+
+// Iterator over every top-level form of a string, in order.  It
+// stops after the first error since the input can't be resynced.
+pub struct Forms<'a> {
+    rdr: Reader<'a>,
+    failed: bool,
+}
+
+impl<'a> Iterator for Forms<'a> {
+    type Item = MalRet;
+
+    fn next(&mut self) -> Option<MalRet> {
+        if self.failed {
+            return None;
+        }
+        let res = match self.rdr.peek() {
+            Ok(None) => return None,
+            Ok(Some(_)) => read_form(&mut self.rdr),
+            Err(e) => Err(e),
+        };
+        self.failed = res.is_err();
+        Some(res)
+    }
+}
+
+fn forms(str: &str, file: Option<Rc<String>>) -> Forms<'_> {
+    Forms {
+        rdr: Reader {
+            lexer: Lexer::new(str, file),
+            peeked: None,
+        },
+        failed: false,
     }
 }
 
+pub fn read_all(str: &str) -> Forms<'_> {
+    forms(str, None)
+}
+
+// like read_all, but records the file, line and column of each form
+pub fn read_all_from<'a>(str: &'a str, file: &str) -> Forms<'a> {
+    forms(str, Some(Rc::new(file.to_string())))
+}
+
 pub fn read_str(str: String) -> MalRet {
-    let tokens = tokenize(&str);
-    //println!("tokens: {:?}", tokens);
-    if tokens.len() == 0 {
-        return error("no input");
-    }
-    read_form(&mut Reader {
-        pos: 0,
-        tokens: tokens,
-    })
+    forms(&str, None)
+        .next()
+        .unwrap_or_else(|| error("no input"))
+}
+
+// like read_str, but records the file, line and column of each form
+pub fn read_str_from(str: String, file: &str) -> MalRet {
+    read_all_from(&str, file)
+        .next()
+        .unwrap_or_else(|| error("no input"))
 }
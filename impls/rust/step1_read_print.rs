@@ -1,22 +1,10 @@
-#[macro_use]
-extern crate lazy_static;
-extern crate fnv;
-extern crate itertools;
-extern crate regex;
-
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
-#[macro_use]
-#[allow(dead_code)]
-mod types;
-use crate::types::format_error;
-mod printer;
-mod reader;
-// TODO: figure out a way to avoid including env
-#[allow(dead_code)]
-mod env;
+extern crate mal;
+use mal::reader;
+use mal::types::format_error;
 
 fn main() {
     // `()` can be used when no completer is required
@@ -31,7 +19,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match reader::read_str(line) {
                         Ok(mv) => {
                             println!("{}", mv.pr_str(true));
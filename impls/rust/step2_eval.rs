@@ -1,30 +1,23 @@
-use std::rc::Rc;
 //use std::collections::HashMap;
 use fnv::FnvHashMap;
 
-#[macro_use]
-extern crate lazy_static;
 extern crate fnv;
-extern crate itertools;
-extern crate regex;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 #[macro_use]
-#[allow(dead_code)]
-mod types;
-use crate::types::MalErr::ErrString;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
-mod printer;
-mod reader;
-// TODO: figure out a way to avoid including env
-#[allow(dead_code)]
-mod env;
+extern crate mal;
+use mal::reader;
+use mal::symbol::Symbol;
+use mal::types::MalErr::ErrString;
+use mal::types::MalVal::{Hash, Int, List, Set, Sym, Vector};
+use mal::types::{
+    error, format_error, func_arity, hash_map, hash_set, MalArgs, MalErr, MalRet, MalVal,
+};
 
-pub type Env = FnvHashMap<String, MalVal>;
+pub type Env = FnvHashMap<Symbol, MalVal>;
 
 // read
 fn read(str: &str) -> MalRet {
@@ -53,11 +46,19 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut kvs: MalArgs = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                kvs.push(eval(k.clone(), env.clone())?);
+                kvs.push(eval(v.clone(), env.clone())?);
+            }
+            hash_map(kvs)
+        }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for x in s.iter() {
+                items.push(eval(x.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(hash_set(items))
         }
         _ => Ok(ast.clone()),
     }
@@ -66,13 +67,13 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
         List(l, _) => {
-            if l.len() == 0 {
+            if l.is_empty() {
                 return Ok(ast);
             }
             match eval_ast(&ast, &env)? {
                 List(ref el, _) => {
-                    let ref f = el[0].clone();
-                    f.apply(el[1..].to_vec())
+                    let f = &el[0].clone();
+                    f.apply(el.rest().to_vec())
                 }
                 _ => error("expected a list"),
             }
@@ -92,9 +93,15 @@ fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     Ok(print(&exp))
 }
 
-fn int_op(op: fn(i64, i64) -> i64, a: MalArgs) -> MalRet {
+// the ops are checked, so overflow is an error in release builds too;
+// only a division fails with a zero second arg
+fn int_op(op: fn(i64, i64) -> Option<i64>, a: &[MalVal]) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Int(a0), Int(a1)) => Ok(Int(op(a0, a1))),
+        (Int(a0), Int(a1)) => match op(a0, a1) {
+            Some(r) => Ok(Int(r)),
+            None if a1 == 0 => error("Divide by zero"),
+            None => error("integer overflow"),
+        },
         _ => error("invalid int_op args"),
     }
 }
@@ -107,10 +114,22 @@ fn main() {
     }
 
     let mut repl_env = Env::default();
-    repl_env.insert("+".to_string(), func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    repl_env.insert("-".to_string(), func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    repl_env.insert("*".to_string(), func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    repl_env.insert("/".to_string(), func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    repl_env.insert(
+        Symbol::intern("+"),
+        func_arity("+", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_add, a)),
+    );
+    repl_env.insert(
+        Symbol::intern("-"),
+        func_arity("-", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_sub, a)),
+    );
+    repl_env.insert(
+        Symbol::intern("*"),
+        func_arity("*", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_mul, a)),
+    );
+    repl_env.insert(
+        Symbol::intern("/"),
+        func_arity("/", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_div, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
@@ -118,7 +137,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match rep(&line, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
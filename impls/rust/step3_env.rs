@@ -1,27 +1,20 @@
-use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
-#[macro_use]
-extern crate lazy_static;
-extern crate fnv;
 extern crate itertools;
-extern crate regex;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 #[macro_use]
-#[allow(dead_code)]
-mod types;
-use crate::types::MalVal::{Hash, Int, List, Nil, Sym, Vector};
-use crate::types::{error, format_error, func, MalArgs, MalErr, MalRet, MalVal};
-mod env;
-mod printer;
-mod reader;
-use crate::env::{env_get, env_new, env_set, env_sets, Env};
+extern crate mal;
+use mal::reader;
+use mal::types::MalVal::{Hash, Int, List, Set, Sym, Vector};
+use mal::types::{
+    error, format_error, func_arity, hash_map, hash_set, MalArgs, MalErr, MalRet, MalVal,
+};
+use mal::env::{env_get, env_new, env_set, env_sets, Env};
 
 // read
 fn read(str: &str) -> MalRet {
@@ -31,7 +24,7 @@ fn read(str: &str) -> MalRet {
 // eval
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
+        Sym(_) => Ok(env_get(env, ast)?),
         List(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
@@ -47,11 +40,19 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut kvs: MalArgs = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                kvs.push(eval(k.clone(), env.clone())?);
+                kvs.push(eval(v.clone(), env.clone())?);
+            }
+            hash_map(kvs)
+        }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for x in s.iter() {
+                items.push(eval(x.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(hash_set(items))
         }
         _ => Ok(ast.clone()),
     }
@@ -60,7 +61,7 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
 fn eval(ast: MalVal, env: Env) -> MalRet {
     match ast.clone() {
         List(l, _) => {
-            if l.len() == 0 {
+            if l.is_empty() {
                 return Ok(ast);
             }
             let a0 = &l[0];
@@ -71,9 +72,9 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 Sym(ref a0sym) if a0sym == "let*" => {
                     let let_env = env_new(Some(env.clone()));
                     let (a1, a2) = (l[1].clone(), l[2].clone());
-                    match a1 {
-                        List(ref binds, _) | Vector(ref binds, _) => {
-                            for (b, e) in binds.iter().tuples() {
+                    match a1.seq_iter() {
+                        Some(binds) => {
+                            for (b, e) in binds.tuples() {
                                 match b {
                                     Sym(_) => {
                                         let _ = env_set(
@@ -88,7 +89,7 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                                 }
                             }
                         }
-                        _ => {
+                        None => {
                             return error("let* with non-List bindings");
                         }
                     };
@@ -96,8 +97,8 @@ fn eval(ast: MalVal, env: Env) -> MalRet {
                 }
                 _ => match eval_ast(&ast, &env)? {
                     List(ref el, _) => {
-                        let ref f = el[0].clone();
-                        f.apply(el[1..].to_vec())
+                        let f = &el[0].clone();
+                        f.apply(el.rest().to_vec())
                     }
                     _ => error("expected a list"),
                 },
@@ -118,9 +119,15 @@ fn rep(str: &str, env: &Env) -> Result<String, MalErr> {
     Ok(print(&exp))
 }
 
-fn int_op(op: fn(i64, i64) -> i64, a: MalArgs) -> MalRet {
+// the ops are checked, so overflow is an error in release builds too;
+// only a division fails with a zero second arg
+fn int_op(op: fn(i64, i64) -> Option<i64>, a: &[MalVal]) -> MalRet {
     match (a[0].clone(), a[1].clone()) {
-        (Int(a0), Int(a1)) => Ok(Int(op(a0, a1))),
+        (Int(a0), Int(a1)) => match op(a0, a1) {
+            Some(r) => Ok(Int(r)),
+            None if a1 == 0 => error("Divide by zero"),
+            None => error("integer overflow"),
+        },
         _ => error("invalid int_op args"),
     }
 }
@@ -133,10 +140,26 @@ fn main() {
     }
 
     let repl_env = env_new(None);
-    env_sets(&repl_env, "+", func(|a: MalArgs| int_op(|i, j| i + j, a)));
-    env_sets(&repl_env, "-", func(|a: MalArgs| int_op(|i, j| i - j, a)));
-    env_sets(&repl_env, "*", func(|a: MalArgs| int_op(|i, j| i * j, a)));
-    env_sets(&repl_env, "/", func(|a: MalArgs| int_op(|i, j| i / j, a)));
+    env_sets(
+        &repl_env,
+        "+",
+        func_arity("+", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_add, a)),
+    );
+    env_sets(
+        &repl_env,
+        "-",
+        func_arity("-", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_sub, a)),
+    );
+    env_sets(
+        &repl_env,
+        "*",
+        func_arity("*", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_mul, a)),
+    );
+    env_sets(
+        &repl_env,
+        "/",
+        func_arity("/", 2, Some(2), |a: &[MalVal]| int_op(i64::checked_div, a)),
+    );
 
     loop {
         let readline = rl.readline("user> ");
@@ -144,7 +167,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match rep(&line, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
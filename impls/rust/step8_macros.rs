@@ -1,28 +1,21 @@
 use std::rc::Rc;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
 use itertools::Itertools;
 
-#[macro_use]
-extern crate lazy_static;
-extern crate fnv;
 extern crate itertools;
-extern crate regex;
 
 extern crate rustyline;
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
 
 #[macro_use]
-mod types;
-use crate::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Str, Sym, Vector};
-use crate::types::{error, format_error, MalArgs, MalErr, MalRet, MalVal};
-mod env;
-mod printer;
-mod reader;
-use crate::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, Env};
-#[macro_use]
-mod core;
+extern crate mal;
+use mal::reader;
+use mal::symbol::Symbol;
+use mal::types::MalVal::{Bool, Func, Hash, List, MalFunc, Nil, Set, Str, Sym, Vector};
+use mal::types::{error, format_error, hash_map, hash_set, MalArgs, MalErr, MalRet, MalVal};
+use mal::env::{env_bind, env_find, env_get, env_new, env_set, env_sets, param_arity, Env};
+use mal::core;
 
 // read
 fn read(str: &str) -> MalRet {
@@ -38,15 +31,15 @@ fn qq_iter(elts: &MalArgs) -> MalVal {
             if v.len() == 2 {
                 if let Sym(ref s) = v[0] {
                     if s == "splice-unquote" {
-                        acc = list![Sym("concat".to_string()), v[1].clone(), acc];
+                        acc = list![Sym(Symbol::intern("concat")), v[1].clone(), acc];
                         continue;
                     }
                 }
             }
         }
-        acc = list![Sym("cons".to_string()), quasiquote(&elt), acc];
+        acc = list![Sym(Symbol::intern("cons")), quasiquote(elt), acc];
     }
-    return acc;
+    acc
 }
 
 fn quasiquote(ast: &MalVal) -> MalVal {
@@ -59,10 +52,10 @@ fn quasiquote(ast: &MalVal) -> MalVal {
                     }
                 }
             }
-            return qq_iter(&v);
+            qq_iter(&v.to_vec())
         },
-        Vector(v, _) => return list![Sym("vec".to_string()), qq_iter(&v)],
-        Hash(_, _) | Sym(_)=> return list![Sym("quote".to_string()), ast.clone()],
+        Vector(v, _) => list![Sym(Symbol::intern("vec")), qq_iter(&v.iter().cloned().collect())],
+        Hash(_, _) | Set(_, _) | Sym(_)=> list![Sym(Symbol::intern("quote")), ast.clone()],
         _ => ast.clone(),
     }
 }
@@ -70,9 +63,9 @@ fn quasiquote(ast: &MalVal) -> MalVal {
 fn is_macro_call(ast: &MalVal, env: &Env) -> Option<(MalVal, MalArgs)> {
     match ast {
         List(v, _) => match v[0] {
-            Sym(ref s) => match env_find(env, s) {
+            Sym(s) => match env_find(env, s) {
                 Some(e) => match env_get(&e, &v[0]) {
-                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v[1..].to_vec())),
+                    Ok(f @ MalFunc { is_macro: true, .. }) => Some((f, v.rest().to_vec())),
                     _ => None,
                 },
                 _ => None,
@@ -94,12 +87,12 @@ fn macroexpand(mut ast: MalVal, env: &Env) -> (bool, MalRet) {
         //println!("macroexpand 2: {:?}", ast);
         was_expanded = true;
     }
-    ((was_expanded, Ok(ast)))
+    (was_expanded, Ok(ast))
 }
 
 fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
     match ast {
-        Sym(_) => Ok(env_get(&env, &ast)?),
+        Sym(_) => Ok(env_get(env, ast)?),
         List(v, _) => {
             let mut lst: MalArgs = vec![];
             for a in v.iter() {
@@ -115,11 +108,19 @@ fn eval_ast(ast: &MalVal, env: &Env) -> MalRet {
             Ok(vector!(lst))
         }
         Hash(hm, _) => {
-            let mut new_hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
+            let mut kvs: MalArgs = vec![];
             for (k, v) in hm.iter() {
-                new_hm.insert(k.to_string(), eval(v.clone(), env.clone())?);
+                kvs.push(eval(k.clone(), env.clone())?);
+                kvs.push(eval(v.clone(), env.clone())?);
+            }
+            hash_map(kvs)
+        }
+        Set(s, _) => {
+            let mut items: MalArgs = vec![];
+            for x in s.iter() {
+                items.push(eval(x.clone(), env.clone())?);
             }
-            Ok(Hash(Rc::new(new_hm), Rc::new(Nil)))
+            Ok(hash_set(items))
         }
         _ => Ok(ast.clone()),
     }
@@ -131,7 +132,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
     'tco: loop {
         ret = match ast.clone() {
             List(l, _) => {
-                if l.len() == 0 {
+                if l.is_empty() {
                     return Ok(ast);
                 }
                 match macroexpand(ast.clone(), &env) {
@@ -143,7 +144,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     _ => (),
                 }
 
-                if l.len() == 0 {
+                if l.is_empty() {
                     return Ok(ast);
                 }
                 let a0 = &l[0];
@@ -154,9 +155,9 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     Sym(ref a0sym) if a0sym == "let*" => {
                         env = env_new(Some(env.clone()));
                         let (a1, a2) = (l[1].clone(), l[2].clone());
-                        match a1 {
-                            List(ref binds, _) | Vector(ref binds, _) => {
-                                for (b, e) in binds.iter().tuples() {
+                        match a1.seq_iter() {
+                            Some(binds) => {
+                                for (b, e) in binds.tuples() {
                                     match b {
                                         Sym(_) => {
                                             let _ = env_set(
@@ -171,7 +172,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                     }
                                 }
                             }
-                            _ => {
+                            None => {
                                 return error("let* with non-List bindings");
                             }
                         };
@@ -198,10 +199,11 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                 &env,
                                 a1.clone(),
                                 MalFunc {
-                                    eval: eval,
+                                    eval,
                                     ast: ast.clone(),
                                     env: env.clone(),
                                     params: params.clone(),
+                                    code: None,
                                     is_macro: true,
                                     meta: Rc::new(Nil),
                                 },
@@ -216,7 +218,7 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                         }
                     }
                     Sym(ref a0sym) if a0sym == "do" => {
-                        match eval_ast(&list!(l[1..l.len() - 1].to_vec()), &env)? {
+                        match eval_ast(&list!(l.iter().skip(1).take(l.len() - 2).cloned().collect()), &env)? {
                             List(_, _) => {
                                 ast = l.last().unwrap_or(&Nil).clone();
                                 continue 'tco;
@@ -241,11 +243,13 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     }
                     Sym(ref a0sym) if a0sym == "fn*" => {
                         let (a1, a2) = (l[1].clone(), l[2].clone());
+                        param_arity(&a1)?;
                         Ok(MalFunc {
-                            eval: eval,
+                            eval,
                             ast: Rc::new(a2),
-                            env: env,
+                            env,
                             params: Rc::new(a1),
+                            code: None,
                             is_macro: false,
                             meta: Rc::new(Nil),
                         })
@@ -259,10 +263,10 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                     }
                     _ => match eval_ast(&ast, &env)? {
                         List(ref el, _) => {
-                            let ref f = el[0].clone();
-                            let args = el[1..].to_vec();
+                            let f = &el[0].clone();
+                            let args = el.rest().to_vec();
                             match f {
-                                Func(_, _) => f.apply(args),
+                                Func(_, _) | Set(_, _) => f.apply(args),
                                 MalFunc {
                                     ast: mast,
                                     env: menv,
@@ -271,7 +275,11 @@ fn eval(mut ast: MalVal, mut env: Env) -> MalRet {
                                 } => {
                                     let a = &**mast;
                                     let p = &**params;
-                                    env = env_bind(Some(menv.clone()), p.clone(), args)?;
+                                    let name = match l[0] {
+                                        Sym(s) => s,
+                                        _ => Symbol::intern("fn*"),
+                                    };
+                                    env = env_bind(Some(menv.clone()), name, p.clone(), args)?;
                                     ast = a.clone();
                                     continue 'tco;
                                 }
@@ -345,7 +353,7 @@ fn main() {
             Ok(line) => {
                 rl.add_history_entry(&line);
                 rl.save_history(".mal-history").unwrap();
-                if line.len() > 0 {
+                if !line.is_empty() {
                     match rep(&line, &repl_env) {
                         Ok(out) => println!("{}", out),
                         Err(e) => println!("Error: {}", format_error(e)),
@@ -0,0 +1,113 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use fnv::FnvHashMap;
+
+// Symbols are interned: each distinct name is stored once in a per-thread
+// table and a Sym holds its index, so comparing and hashing symbols (env
+// lookups, special form dispatch) works on an integer.  Ids are only
+// meaningful on the thread that made them, which is fine as mal values
+// never leave it.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Symbol(u32);
+
+struct Table {
+    names: Vec<Rc<str>>,
+    ids: FnvHashMap<Rc<str>, Symbol>,
+}
+
+// The symbols eval and the reader look for are interned first, in this
+// order, so that each has a fixed id usable as a match pattern.
+macro_rules! known {
+    ($($id:ident = $name:expr,)*) => {
+        const KNOWN: &[&str] = &[$($name),*];
+        known!(@id 0; $($id)*);
+    };
+    (@id $n:expr; $id:ident $($rest:ident)*) => {
+        pub const $id: Symbol = Symbol($n);
+        known!(@id $n + 1; $($rest)*);
+    };
+    (@id $n:expr;) => {};
+}
+
+known! {
+    DEF = "def!",
+    LET = "let*",
+    DO = "do",
+    IF = "if",
+    FN = "fn*",
+    QUOTE = "quote",
+    QUASIQUOTE = "quasiquote",
+    QUASIQUOTEEXPAND = "quasiquoteexpand",
+    UNQUOTE = "unquote",
+    SPLICE_UNQUOTE = "splice-unquote",
+    DEFMACRO = "defmacro!",
+    MACROEXPAND = "macroexpand",
+    TRY = "try*",
+    CATCH = "catch*",
+    LAZY_SEQ = "lazy-seq",
+    EVAL = "eval",
+    AMP = "&",
+    CONS = "cons",
+    CONCAT = "concat",
+    VEC = "vec",
+    WITH_META = "with-meta",
+    DEREF = "deref",
+}
+
+thread_local! {
+    static TABLE: RefCell<Table> = RefCell::new(Table::new());
+}
+
+impl Table {
+    fn new() -> Table {
+        let mut t = Table {
+            names: vec![],
+            ids: FnvHashMap::default(),
+        };
+        for name in KNOWN {
+            t.intern(name);
+        }
+        t
+    }
+
+    fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(&id) = self.ids.get(name) {
+            return id;
+        }
+        let id = Symbol(self.names.len() as u32);
+        let name: Rc<str> = name.into();
+        self.names.push(name.clone());
+        self.ids.insert(name, id);
+        id
+    }
+}
+
+impl Symbol {
+    pub fn intern(name: &str) -> Symbol {
+        TABLE.with(|t| t.borrow_mut().intern(name))
+    }
+
+    pub fn name(self) -> Rc<str> {
+        TABLE.with(|t| t.borrow().names[self.0 as usize].clone())
+    }
+}
+
+impl PartialEq<str> for Symbol {
+    fn eq(&self, other: &str) -> bool {
+        TABLE.with(|t| &*t.borrow().names[self.0 as usize] == other)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.name())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Symbol({:?})", &*self.name())
+    }
+}
@@ -1,40 +1,129 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::hash::{Hash as StdHash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
 use std::rc::Rc;
+use std::sync::Once;
 //use std::collections::HashMap;
-use fnv::FnvHashMap;
+use fnv::{FnvBuildHasher, FnvHashSet, FnvHasher};
+use im_rc;
 use itertools::Itertools;
+use num_bigint;
+use num_rational::BigRational;
+use num_traits::ToPrimitive;
+use serde::{de, ser};
 
+use crate::analyze::Lambda;
 use crate::env::{env_bind, Env};
-use crate::types::MalErr::{ErrMalVal, ErrString};
-use crate::types::MalVal::{Atom, Bool, Func, Hash, Int, List, MalFunc, Nil, Str, Sym, Vector};
+use crate::eval;
+use crate::gc;
+use crate::lazy::Lazy;
+use crate::list;
+use crate::list::MalList;
+use crate::symbol::{self, Symbol};
+use crate::types::MalErr::{ErrAt, ErrMalVal, ErrString};
+use crate::types::MalVal::{
+    Atom, BigInt, Bool, Float, Func, Hash, Int, Keyword, LazySeq, List, MalFunc, Nil, Ratio, Set,
+    Str, Sym, Vector,
+};
+use crate::vm::{self, Closure};
+
+#[derive(Debug, Clone)]
+pub enum Compiled {
+    Tree(Rc<Lambda>),
+    Bytecode(Rc<Closure>),
+}
 
 #[derive(Debug, Clone)]
 pub enum MalVal {
     Nil,
     Bool(bool),
     Int(i64),
-    //Float(f64),
+    BigInt(Rc<num_bigint::BigInt>),
+    Ratio(Rc<BigRational>),
+    Float(f64),
     Str(String),
-    Sym(String),
-    List(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Vector(Rc<Vec<MalVal>>, Rc<MalVal>),
-    Hash(Rc<FnvHashMap<String, MalVal>>, Rc<MalVal>),
-    Func(fn(MalArgs) -> MalRet, Rc<MalVal>),
+    // made by keyword(), which interns it
+    Keyword(Rc<str>),
+    Sym(Symbol),
+    List(MalList, Rc<MalVal>),
+    Vector(Rc<MalVec>, Rc<MalVal>),
+    LazySeq(Rc<Lazy>),
+    Hash(Rc<MalMap>, Rc<MalVal>),
+    Set(Rc<MalSet>, Rc<MalVal>),
+    Func(Rc<Native>, Rc<MalVal>),
     MalFunc {
         eval: fn(ast: MalVal, env: Env) -> MalRet,
         ast: Rc<MalVal>,
         env: Env,
         params: Rc<MalVal>,
+        // the analyzed or compiled body, for functions made by stepA
+        code: Option<Compiled>,
         is_macro: bool,
         meta: Rc<MalVal>,
     },
     Atom(Rc<RefCell<MalVal>>),
 }
 
+// relaxed radix balanced trie: conj, nth and assoc are O(log n)
+pub type MalVec = im_rc::Vector<MalVal>;
+
+// persistent hash array mapped tries, so assoc, dissoc, conj and disj share
+// structure with the original instead of copying it
+pub type MalMap = im_rc::HashMap<MalVal, MalVal, FnvBuildHasher>;
+pub type MalSet = im_rc::HashSet<MalVal, FnvBuildHasher>;
+
+pub type NativeFn = dyn Fn(&[MalVal]) -> MalRet;
+
+// A builtin implemented in Rust. Unlike a bare fn pointer the closure
+// can capture state, such as an output buffer or a handle.
+pub struct Native {
+    pub name: String,
+    // min and max number of args; no max means variadic
+    pub arity: (usize, Option<usize>),
+    pub f: Box<NativeFn>,
+}
+
+impl Native {
+    pub fn call(&self, args: &[MalVal]) -> MalRet {
+        let (min, max) = self.arity;
+        if args.len() < min || max.is_some_and(|max| args.len() > max) {
+            return error(&format!(
+                "Wrong number of args ({}) passed to {}",
+                args.len(),
+                self.name
+            ));
+        }
+        (self.f)(args)
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Native({})", self.name)
+    }
+}
+
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum MalErr {
     ErrString(String),
     ErrMalVal(MalVal),
+    ErrAt(Box<MalErr>, Span),
+}
+
+// source position of a form read from a named file
+#[derive(Debug, Clone)]
+pub struct Span {
+    pub file: Rc<String>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
 }
 
 pub type MalArgs = Vec<MalVal>;
@@ -42,23 +131,31 @@ pub type MalRet = Result<MalVal, MalErr>;
 
 // type utility macros
 
+#[macro_export]
 macro_rules! list {
   ($seq:expr) => {{
-    List(Rc::new($seq),Rc::new(Nil))
+    let v: Vec<$crate::types::MalVal> = $seq;
+    $crate::types::MalVal::List($crate::list::MalList::from(v),
+                                ::std::rc::Rc::new($crate::types::MalVal::Nil))
   }};
   [$($args:expr),*] => {{
-    let v: Vec<MalVal> = vec![$($args),*];
-    List(Rc::new(v),Rc::new(Nil))
+    let v: Vec<$crate::types::MalVal> = vec![$($args),*];
+    $crate::types::MalVal::List($crate::list::MalList::from(v),
+                                ::std::rc::Rc::new($crate::types::MalVal::Nil))
   }}
 }
 
+#[macro_export]
 macro_rules! vector {
   ($seq:expr) => {{
-    Vector(Rc::new($seq),Rc::new(Nil))
+    let v: Vec<$crate::types::MalVal> = $seq;
+    $crate::types::MalVal::Vector(::std::rc::Rc::new($crate::types::MalVec::from(v)),
+                                  ::std::rc::Rc::new($crate::types::MalVal::Nil))
   }};
   [$($args:expr),*] => {{
-    let v: Vec<MalVal> = vec![$($args),*];
-    Vector(Rc::new(v),Rc::new(Nil))
+    let v: Vec<$crate::types::MalVal> = vec![$($args),*];
+    $crate::types::MalVal::Vector(::std::rc::Rc::new($crate::types::MalVec::from(v)),
+                                  ::std::rc::Rc::new($crate::types::MalVal::Nil))
   }}
 }
 
@@ -68,29 +165,169 @@ pub fn error(s: &str) -> MalRet {
     Err(ErrString(s.to_string()))
 }
 
+thread_local! {
+    // how many catch_panic calls are running on this thread
+    static CATCHING: Cell<usize> = const { Cell::new(0) };
+}
+
+// The default panic hook prints the message and a backtrace, which is
+// just noise for a panic that becomes a mal error.  This one says nothing
+// on a thread inside catch_panic, and leaves any other panic to the hook
+// that was there before.
+fn quiet_caught_panics() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.try_with(|c| c.get()).unwrap_or(0) == 0 {
+                prev(info);
+            }
+        }));
+    });
+}
+
+// Runs f, turning a Rust panic into a mal error carrying the panic
+// message, so a bug in a builtin doesn't take the interpreter down.
+pub fn catch_panic<F: FnOnce() -> MalRet>(f: F) -> MalRet {
+    quiet_caught_panics();
+    CATCHING.with(|c| c.set(c.get() + 1));
+    let res = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.with(|c| c.set(c.get() - 1));
+    match res {
+        Ok(ret) => ret,
+        Err(p) => {
+            let msg = match p.downcast_ref::<&str>() {
+                Some(s) => s.to_string(),
+                None => match p.downcast_ref::<String>() {
+                    Some(s) => s.clone(),
+                    None => "unknown panic".to_string(),
+                },
+            };
+            error(&format!("panic: {}", msg))
+        }
+    }
+}
+
 pub fn format_error(e: MalErr) -> String {
-    match e {
-        ErrString(s) => s.clone(),
-        ErrMalVal(mv) => mv.pr_str(true),
+    e.to_string()
+}
+
+impl fmt::Display for MalErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ErrString(s) => write!(f, "{}", s),
+            ErrMalVal(mv) => write!(f, "{}", mv.pr_str(true)),
+            ErrAt(e, span) => write!(f, "{}: {}", span, e),
+        }
+    }
+}
+
+impl std::error::Error for MalErr {}
+
+impl MalErr {
+    // attach a position unless the error already has a more precise one
+    pub fn at(self, span: Option<Span>) -> MalErr {
+        match (self, span) {
+            (e @ ErrAt(..), _) | (e, None) => e,
+            (e, Some(span)) => ErrAt(Box::new(e), span),
+        }
+    }
+
+    // the value bound by catch*, without any position information
+    pub fn value(&self) -> MalVal {
+        match self {
+            ErrString(s) => Str(s.to_string()),
+            ErrMalVal(mv) => mv.clone(),
+            ErrAt(e, _) => e.value(),
+        }
+    }
+}
+
+// BigInts that fit in an i64 become plain ints
+pub fn integer(n: num_bigint::BigInt) -> MalVal {
+    match n.to_i64() {
+        Some(i) => Int(i),
+        None => BigInt(Rc::new(n)),
+    }
+}
+
+// ratios that reduce to a whole number become plain integers
+pub fn ratio(r: BigRational) -> MalVal {
+    if r.is_integer() {
+        integer(r.to_integer())
+    } else {
+        Ratio(Rc::new(r))
     }
 }
 
+thread_local! {
+    static KEYWORDS: RefCell<FnvHashSet<Rc<str>>> = RefCell::new(FnvHashSet::default());
+}
+
+// keywords are interned, so equal keywords share their name
+pub fn keyword(name: &str) -> MalVal {
+    KEYWORDS.with(|kws| {
+        let mut kws = kws.borrow_mut();
+        if let Some(k) = kws.get(name) {
+            return Keyword(k.clone());
+        }
+        let k: Rc<str> = Rc::from(name);
+        kws.insert(k.clone());
+        Keyword(k)
+    })
+}
+
 pub fn atom(mv: &MalVal) -> MalVal {
-    Atom(Rc::new(RefCell::new(mv.clone())))
+    let a = Rc::new(RefCell::new(mv.clone()));
+    gc::track_atom(&a);
+    Atom(a)
 }
 
 impl MalVal {
     pub fn keyword(&self) -> MalRet {
         match self {
-            Str(s) if s.starts_with("\u{29e}") => Ok(Str(s.to_string())),
-            Str(s) => Ok(Str(format!("\u{29e}{}", s))),
+            Keyword(_) => Ok(self.clone()),
+            Str(s) => Ok(keyword(s)),
             _ => error("invalid type for keyword"),
         }
     }
 
+    pub fn seq_iter(&self) -> Option<SeqIter<'_>> {
+        match self {
+            List(l, _) => Some(SeqIter::List(l.iter())),
+            Vector(v, _) => Some(SeqIter::Vector(v.iter())),
+            _ => None,
+        }
+    }
+
+    pub fn sequential_q(&self) -> bool {
+        matches!(self, List(_, _) | Vector(_, _) | LazySeq(_))
+    }
+
+    // first element and rest of a sequence, or None when it is empty
+    pub fn uncons(&self) -> Result<Option<(MalVal, MalVal)>, MalErr> {
+        match self {
+            List(l, _) => Ok(l.first().map(|x| (x.clone(), List(l.rest(), Rc::new(Nil))))),
+            Vector(v, _) => Ok(v
+                .front()
+                .map(|x| (x.clone(), Vector(Rc::new(v.skip(1)), Rc::new(Nil))))),
+            LazySeq(l) => l.realize(),
+            Nil => Ok(None),
+            _ => Err(ErrString(format!("not a sequence: {}", self.pr_str(true)))),
+        }
+    }
+
+    // elements of any sequence, realizing lazy ones as it goes
+    pub fn walk(&self) -> Walk {
+        Walk(self.clone())
+    }
+
     pub fn empty_q(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Bool(l.len() == 0)),
+            List(l, _) => Ok(Bool(l.is_empty())),
+            Vector(v, _) => Ok(Bool(v.is_empty())),
+            LazySeq(l) => Ok(Bool(l.realize()?.is_none())),
+            Set(s, _) => Ok(Bool(s.is_empty())),
             Nil => Ok(Bool(true)),
             _ => error("invalid type for empty?"),
         }
@@ -98,7 +335,10 @@ impl MalVal {
 
     pub fn count(&self) -> MalRet {
         match self {
-            List(l, _) | Vector(l, _) => Ok(Int(l.len() as i64)),
+            List(l, _) => Ok(Int(l.len() as i64)),
+            Vector(v, _) => Ok(Int(v.len() as i64)),
+            LazySeq(_) => Ok(Int(self.walk().try_fold(0, |n, x| x.map(|_| n + 1))?)),
+            Set(s, _) => Ok(Int(s.len() as i64)),
             Nil => Ok(Int(0)),
             _ => error("invalid type for count"),
         }
@@ -106,7 +346,19 @@ impl MalVal {
 
     pub fn apply(&self, args: MalArgs) -> MalRet {
         match *self {
-            Func(f, _) => f(args),
+            // a panic in a builtin is thrown like an error, whichever
+            // evaluator called it
+            Func(ref f, _) => catch_panic(|| f.call(&args)),
+            MalFunc {
+                code: Some(Compiled::Tree(ref lambda)),
+                ref env,
+                ..
+            } => eval::call(lambda, env, args),
+            MalFunc {
+                code: Some(Compiled::Bytecode(ref closure)),
+                ref env,
+                ..
+            } => vm::call(closure, env, args),
             MalFunc {
                 eval,
                 ref ast,
@@ -116,20 +368,19 @@ impl MalVal {
             } => {
                 let a = &**ast;
                 let p = &**params;
-                let fn_env = env_bind(Some(env.clone()), p.clone(), args)?;
+                let fn_env = env_bind(Some(env.clone()), symbol::FN, p.clone(), args)?;
                 Ok(eval(a.clone(), fn_env)?)
             }
+            // sets look up their argument
+            Set(ref s, _) => match args.len() {
+                1 if s.contains(&args[0]) => Ok(args[0].clone()),
+                1 => Ok(Nil),
+                _ => error("set called with wrong number of args"),
+            },
             _ => error("attempt to call non-function"),
         }
     }
 
-    pub fn keyword_q(&self) -> bool {
-        match self {
-            Str(s) if s.starts_with("\u{29e}") => true,
-            _ => false,
-        }
-    }
-
     pub fn deref(&self) -> MalRet {
         match self {
             Atom(a) => Ok(a.borrow().clone()),
@@ -162,9 +413,9 @@ impl MalVal {
 
     pub fn get_meta(&self) -> MalRet {
         match self {
-            List(_, meta) | Vector(_, meta) | Hash(_, meta) => Ok((&**meta).clone()),
-            Func(_, meta) => Ok((&**meta).clone()),
-            MalFunc { meta, .. } => Ok((&**meta).clone()),
+            List(_, meta) | Vector(_, meta) | Hash(_, meta) | Set(_, meta) => Ok((**meta).clone()),
+            Func(_, meta) => Ok((**meta).clone()),
+            MalFunc { meta, .. } => Ok((**meta).clone()),
             _ => error("meta not supported by type"),
         }
     }
@@ -174,9 +425,10 @@ impl MalVal {
             List(_, ref mut meta)
             | Vector(_, ref mut meta)
             | Hash(_, ref mut meta)
+            | Set(_, ref mut meta)
             | Func(_, ref mut meta)
             | MalFunc { ref mut meta, .. } => {
-                *meta = Rc::new((&*new_meta).clone());
+                *meta = Rc::new((*new_meta).clone());
             }
             _ => return error("with-meta not supported by type"),
         };
@@ -184,57 +436,861 @@ impl MalVal {
     }
 }
 
+// elements of a list or vector, front to back
+pub enum SeqIter<'a> {
+    List(list::Iter<'a>),
+    Vector(im_rc::vector::Iter<'a, MalVal>),
+}
+
+impl<'a> Iterator for SeqIter<'a> {
+    type Item = &'a MalVal;
+
+    fn next(&mut self) -> Option<&'a MalVal> {
+        match self {
+            SeqIter::List(it) => it.next(),
+            SeqIter::Vector(it) => it.next(),
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self {
+            SeqIter::List(it) => it.size_hint(),
+            SeqIter::Vector(it) => it.size_hint(),
+        }
+    }
+}
+
+impl<'a> ExactSizeIterator for SeqIter<'a> {}
+
+pub struct Walk(MalVal);
+
+impl Iterator for Walk {
+    type Item = MalRet;
+
+    fn next(&mut self) -> Option<MalRet> {
+        match self.0.uncons() {
+            Ok(Some((x, rest))) => {
+                self.0 = rest;
+                Some(Ok(x))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                self.0 = Nil;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
 impl PartialEq for MalVal {
     fn eq(&self, other: &MalVal) -> bool {
         match (self, other) {
             (Nil, Nil) => true,
             (Bool(ref a), Bool(ref b)) => a == b,
             (Int(ref a), Int(ref b)) => a == b,
+            (BigInt(ref a), BigInt(ref b)) => a == b,
+            (Int(ref a), BigInt(ref b)) | (BigInt(ref b), Int(ref a)) => {
+                **b == num_bigint::BigInt::from(*a)
+            }
+            (Ratio(ref a), Ratio(ref b)) => a == b,
+            (Float(ref a), Float(ref b)) => a == b,
             (Str(ref a), Str(ref b)) => a == b,
+            (Keyword(ref a), Keyword(ref b)) => Rc::ptr_eq(a, b),
             (Sym(ref a), Sym(ref b)) => a == b,
-            (List(ref a, _), List(ref b, _))
-            | (Vector(ref a, _), Vector(ref b, _))
-            | (List(ref a, _), Vector(ref b, _))
-            | (Vector(ref a, _), List(ref b, _)) => a == b,
+            (List(_, _), _) | (Vector(_, _), _) | (LazySeq(_), _) => {
+                match (self.seq_iter(), other.seq_iter()) {
+                    (Some(a), Some(b)) => a.len() == b.len() && a.eq(b),
+                    _ if other.sequential_q() => {
+                        let (mut a, mut b) = (self.walk(), other.walk());
+                        loop {
+                            match (a.next(), b.next()) {
+                                (None, None) => return true,
+                                (Some(Ok(x)), Some(Ok(y))) if x == y => (),
+                                _ => return false,
+                            }
+                        }
+                    }
+                    _ => false,
+                }
+            }
             (Hash(ref a, _), Hash(ref b, _)) => a == b,
+            (Set(ref a, _), Set(ref b, _)) => a == b,
             (MalFunc { .. }, MalFunc { .. }) => false,
             _ => false,
         }
     }
 }
 
-pub fn func(f: fn(MalArgs) -> MalRet) -> MalVal {
-    Func(f, Rc::new(Nil))
+// NaN floats and functions never compare equal, so as map keys they can be
+// stored but not found again
+impl Eq for MalVal {}
+
+// must agree with PartialEq: lists and vectors hash alike, as do an Int and
+// a BigInt holding the same value
+impl StdHash for MalVal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        match self {
+            Nil => 0.hash(state),
+            Bool(b) => {
+                1.hash(state);
+                b.hash(state)
+            }
+            Int(i) => {
+                2.hash(state);
+                i.hash(state)
+            }
+            BigInt(b) => {
+                2.hash(state);
+                match b.to_i64() {
+                    Some(i) => i.hash(state),
+                    None => b.hash(state),
+                }
+            }
+            Ratio(r) => {
+                3.hash(state);
+                r.hash(state)
+            }
+            Float(f) => {
+                4.hash(state);
+                // 0.0 == -0.0
+                (if *f == 0.0 { 0.0f64 } else { *f }).to_bits().hash(state)
+            }
+            Str(s) => {
+                5.hash(state);
+                s.hash(state)
+            }
+            // by name like symbols, as the address an interned keyword
+            // got would make the order maps iterate in vary between runs
+            Keyword(k) => {
+                6.hash(state);
+                k.hash(state)
+            }
+            // by name, so the order maps iterate in doesn't depend on
+            // which symbols happened to be interned first
+            Sym(s) => {
+                7.hash(state);
+                s.name().hash(state)
+            }
+            List(_, _) | Vector(_, _) => {
+                8.hash(state);
+                if let Some(it) = self.seq_iter() {
+                    it.len().hash(state);
+                    for x in it {
+                        x.hash(state)
+                    }
+                }
+            }
+            LazySeq(_) => {
+                8.hash(state);
+                let items: Vec<MalVal> = self.walk().filter_map(Result::ok).collect();
+                items.len().hash(state);
+                for x in items.iter() {
+                    x.hash(state)
+                }
+            }
+            Hash(hm, _) => {
+                // entry order is unspecified, so combine entry hashes
+                // commutatively
+                9.hash(state);
+                hm.len().hash(state);
+                let mut sum: u64 = 0;
+                for (k, v) in hm.iter() {
+                    let mut h = FnvHasher::default();
+                    k.hash(&mut h);
+                    v.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                sum.hash(state)
+            }
+            Set(s, _) => {
+                10.hash(state);
+                s.len().hash(state);
+                let mut sum: u64 = 0;
+                for x in s.iter() {
+                    let mut h = FnvHasher::default();
+                    x.hash(&mut h);
+                    sum = sum.wrapping_add(h.finish());
+                }
+                sum.hash(state)
+            }
+            Func(..) | MalFunc { .. } | Atom(_) => 11.hash(state),
+        }
+    }
 }
 
-pub fn _assoc(mut hm: FnvHashMap<String, MalVal>, kvs: MalArgs) -> MalRet {
+pub fn func<F: Fn(&[MalVal]) -> MalRet + 'static>(name: &str, f: F) -> MalVal {
+    func_arity(name, 0, None, f)
+}
+
+pub fn func_arity<F>(name: &str, min: usize, max: Option<usize>, f: F) -> MalVal
+where
+    F: Fn(&[MalVal]) -> MalRet + 'static,
+{
+    Func(
+        Rc::new(Native {
+            name: name.to_string(),
+            arity: (min, max),
+            f: Box::new(f),
+        }),
+        Rc::new(Nil),
+    )
+}
+
+pub fn _assoc(mut hm: MalMap, kvs: MalArgs) -> MalRet {
     if kvs.len() % 2 != 0 {
         return error("odd number of elements");
     }
     for (k, v) in kvs.iter().tuples() {
-        match k {
-            Str(s) => {
-                hm.insert(s.to_string(), v.clone());
-            }
-            _ => return error("key is not string"),
-        }
+        hm.insert(k.clone(), v.clone());
     }
     Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
-pub fn _dissoc(mut hm: FnvHashMap<String, MalVal>, ks: MalArgs) -> MalRet {
+pub fn _dissoc(mut hm: MalMap, ks: MalArgs) -> MalRet {
     for k in ks.iter() {
-        match k {
-            Str(ref s) => {
-                hm.remove(s);
-            }
-            _ => return error("key is not string"),
-        }
+        hm.remove(k);
     }
     Ok(Hash(Rc::new(hm), Rc::new(Nil)))
 }
 
 pub fn hash_map(kvs: MalArgs) -> MalRet {
-    let hm: FnvHashMap<String, MalVal> = FnvHashMap::default();
-    _assoc(hm, kvs)
+    _assoc(MalMap::default(), kvs)
+}
+
+pub fn hash_set(items: MalArgs) -> MalVal {
+    let s: MalSet = items.into_iter().collect();
+    Set(Rc::new(s), Rc::new(Nil))
+}
+
+pub fn _disj(mut s: MalSet, ks: MalArgs) -> MalVal {
+    for k in ks.iter() {
+        s.remove(k);
+    }
+    Set(Rc::new(s), Rc::new(Nil))
+}
+
+// serde bridge: any Serialize type can be turned into a MalVal and any
+// MalVal deserialized into a Deserialize type.  Struct fields and enum
+// variants become keywords, sequences become vectors and maps hash-maps.
+// A variant with data is a single entry map, {:Variant data}.
+
+pub fn to_mal<T: ser::Serialize + ?Sized>(value: &T) -> MalRet {
+    value.serialize(ValueSerializer)
+}
+
+pub fn from_mal<T: de::DeserializeOwned>(value: &MalVal) -> Result<T, MalErr> {
+    T::deserialize(ValueDeserializer(value)).map_err(|e| ErrString(e.to_string()))
+}
+
+impl ser::Error for MalErr {
+    fn custom<T: fmt::Display>(msg: T) -> MalErr {
+        ErrString(msg.to_string())
+    }
+}
+
+pub struct ValueSerializer;
+
+fn wrap_variant(variant: Option<&'static str>, val: MalVal) -> MalRet {
+    match variant {
+        Some(name) => hash_map(vec![keyword(name), val]),
+        None => Ok(val),
+    }
+}
+
+impl ser::Serializer for ValueSerializer {
+    type Ok = MalVal;
+    type Error = MalErr;
+    type SerializeSeq = SerializeVec;
+    type SerializeTuple = SerializeVec;
+    type SerializeTupleStruct = SerializeVec;
+    type SerializeTupleVariant = SerializeVec;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeMap;
+
+    fn serialize_bool(self, v: bool) -> MalRet {
+        Ok(Bool(v))
+    }
+    fn serialize_i8(self, v: i8) -> MalRet {
+        Ok(Int(v.into()))
+    }
+    fn serialize_i16(self, v: i16) -> MalRet {
+        Ok(Int(v.into()))
+    }
+    fn serialize_i32(self, v: i32) -> MalRet {
+        Ok(Int(v.into()))
+    }
+    fn serialize_i64(self, v: i64) -> MalRet {
+        Ok(Int(v))
+    }
+    fn serialize_i128(self, v: i128) -> MalRet {
+        Ok(integer(v.into()))
+    }
+    fn serialize_u8(self, v: u8) -> MalRet {
+        Ok(Int(v.into()))
+    }
+    fn serialize_u16(self, v: u16) -> MalRet {
+        Ok(Int(v.into()))
+    }
+    fn serialize_u32(self, v: u32) -> MalRet {
+        Ok(Int(v.into()))
+    }
+    fn serialize_u64(self, v: u64) -> MalRet {
+        Ok(integer(v.into()))
+    }
+    fn serialize_u128(self, v: u128) -> MalRet {
+        Ok(integer(v.into()))
+    }
+    fn serialize_f32(self, v: f32) -> MalRet {
+        Ok(Float(v.into()))
+    }
+    fn serialize_f64(self, v: f64) -> MalRet {
+        Ok(Float(v))
+    }
+    fn serialize_char(self, v: char) -> MalRet {
+        Ok(Str(v.to_string()))
+    }
+    fn serialize_str(self, v: &str) -> MalRet {
+        Ok(Str(v.to_string()))
+    }
+    fn serialize_bytes(self, v: &[u8]) -> MalRet {
+        Ok(vector!(v.iter().map(|b| Int((*b).into())).collect()))
+    }
+    fn serialize_none(self) -> MalRet {
+        Ok(Nil)
+    }
+    fn serialize_some<T: ser::Serialize + ?Sized>(self, value: &T) -> MalRet {
+        value.serialize(self)
+    }
+    fn serialize_unit(self) -> MalRet {
+        Ok(Nil)
+    }
+    fn serialize_unit_struct(self, _name: &'static str) -> MalRet {
+        Ok(Nil)
+    }
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+    ) -> MalRet {
+        Ok(keyword(variant))
+    }
+    fn serialize_newtype_struct<T: ser::Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> MalRet {
+        value.serialize(self)
+    }
+    fn serialize_newtype_variant<T: ser::Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> MalRet {
+        wrap_variant(Some(variant), to_mal(value)?)
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeVec, MalErr> {
+        Ok(SerializeVec {
+            variant: None,
+            items: Vec::with_capacity(len.unwrap_or(0)),
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<SerializeVec, MalErr> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec, MalErr> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVec, MalErr> {
+        Ok(SerializeVec {
+            variant: Some(variant),
+            items: Vec::with_capacity(len),
+        })
+    }
+    fn serialize_map(self, _len: Option<usize>) -> Result<SerializeMap, MalErr> {
+        Ok(SerializeMap {
+            variant: None,
+            kvs: vec![],
+            key: None,
+        })
+    }
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap, MalErr> {
+        self.serialize_map(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _idx: u32,
+        variant: &'static str,
+        _len: usize,
+    ) -> Result<SerializeMap, MalErr> {
+        Ok(SerializeMap {
+            variant: Some(variant),
+            kvs: vec![],
+            key: None,
+        })
+    }
+}
+
+pub struct SerializeVec {
+    variant: Option<&'static str>,
+    items: MalArgs,
+}
+
+impl SerializeVec {
+    fn push<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MalErr> {
+        self.items.push(to_mal(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> MalRet {
+        wrap_variant(self.variant, vector!(self.items))
+    }
+}
+
+impl ser::SerializeSeq for SerializeVec {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_element<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MalErr> {
+        self.push(value)
+    }
+    fn end(self) -> MalRet {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTuple for SerializeVec {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_element<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MalErr> {
+        self.push(value)
+    }
+    fn end(self) -> MalRet {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeVec {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MalErr> {
+        self.push(value)
+    }
+    fn end(self) -> MalRet {
+        self.finish()
+    }
+}
+
+impl ser::SerializeTupleVariant for SerializeVec {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MalErr> {
+        self.push(value)
+    }
+    fn end(self) -> MalRet {
+        self.finish()
+    }
+}
+
+pub struct SerializeMap {
+    variant: Option<&'static str>,
+    kvs: MalArgs,
+    key: Option<MalVal>,
+}
+
+impl SerializeMap {
+    fn field<T: ser::Serialize + ?Sized>(&mut self, key: &str, value: &T) -> Result<(), MalErr> {
+        self.kvs.push(keyword(key));
+        self.kvs.push(to_mal(value)?);
+        Ok(())
+    }
+
+    fn finish(self) -> MalRet {
+        wrap_variant(self.variant, hash_map(self.kvs)?)
+    }
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_key<T: ser::Serialize + ?Sized>(&mut self, key: &T) -> Result<(), MalErr> {
+        self.key = Some(to_mal(key)?);
+        Ok(())
+    }
+    fn serialize_value<T: ser::Serialize + ?Sized>(&mut self, value: &T) -> Result<(), MalErr> {
+        match self.key.take() {
+            Some(k) => {
+                self.kvs.push(k);
+                self.kvs.push(to_mal(value)?);
+                Ok(())
+            }
+            None => Err(ErrString("map value serialized before its key".to_string())),
+        }
+    }
+    fn end(self) -> MalRet {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: ser::Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), MalErr> {
+        self.field(key, value)
+    }
+    fn end(self) -> MalRet {
+        self.finish()
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeMap {
+    type Ok = MalVal;
+    type Error = MalErr;
+    fn serialize_field<T: ser::Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), MalErr> {
+        self.field(key, value)
+    }
+    fn end(self) -> MalRet {
+        self.finish()
+    }
+}
+
+// A deserialization error and where in the value it happened, such as
+// .users[2].name; the path is built up as the error is returned through
+// each enclosing vector and map.
+#[derive(Debug)]
+pub struct DeError {
+    path: String,
+    msg: String,
+}
+
+impl DeError {
+    fn within(mut self, seg: &str) -> DeError {
+        self.path.insert_str(0, seg);
+        self
+    }
+}
+
+impl fmt::Display for DeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.path.is_empty() {
+            write!(f, "{}", self.msg)
+        } else {
+            write!(f, "{}: {}", self.path, self.msg)
+        }
+    }
+}
+
+impl std::error::Error for DeError {}
+
+impl de::Error for DeError {
+    fn custom<T: fmt::Display>(msg: T) -> DeError {
+        DeError {
+            path: String::new(),
+            msg: msg.to_string(),
+        }
+    }
+}
+
+// keyword keys read like fields, anything else like an index
+fn path_segment(key: &MalVal) -> String {
+    match key {
+        Keyword(k) => format!(".{}", k),
+        _ => format!("[{}]", key.pr_str(true)),
+    }
+}
+
+fn unexpected(v: &MalVal) -> de::Unexpected<'_> {
+    match v {
+        Nil => de::Unexpected::Unit,
+        Bool(b) => de::Unexpected::Bool(*b),
+        Int(i) => de::Unexpected::Signed(*i),
+        Float(f) => de::Unexpected::Float(*f),
+        Str(s) => de::Unexpected::Str(s),
+        BigInt(_) | Ratio(_) => de::Unexpected::Other("number"),
+        Keyword(_) => de::Unexpected::Other("keyword"),
+        Sym(_) => de::Unexpected::Other("symbol"),
+        List(..) | Vector(..) | Set(..) | LazySeq(_) => de::Unexpected::Seq,
+        Hash(..) => de::Unexpected::Map,
+        Func(..) | MalFunc { .. } => de::Unexpected::Other("function"),
+        Atom(_) => de::Unexpected::Other("atom"),
+    }
+}
+
+pub struct ValueDeserializer<'a>(pub &'a MalVal);
+
+impl<'a> ValueDeserializer<'a> {
+    fn seq<'de, V, I>(visitor: V, iter: I) -> Result<V::Value, DeError>
+    where
+        V: de::Visitor<'de>,
+        I: Iterator<Item = &'a MalVal>,
+    {
+        let mut seq = SeqAccess { iter, idx: 0 };
+        let ret = visitor.visit_seq(&mut seq)?;
+        match seq.iter.count() {
+            0 => Ok(ret),
+            rest => Err(de::Error::invalid_length(seq.idx + rest, &"fewer elements")),
+        }
+    }
+}
+
+impl<'a, 'de> de::Deserializer<'de> for ValueDeserializer<'a> {
+    type Error = DeError;
+
+    fn deserialize_any<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            Nil => visitor.visit_unit(),
+            Bool(b) => visitor.visit_bool(*b),
+            Int(i) => visitor.visit_i64(*i),
+            BigInt(n) => {
+                if let Some(u) = n.to_u64() {
+                    visitor.visit_u64(u)
+                } else if let Some(i) = n.to_i128() {
+                    visitor.visit_i128(i)
+                } else if let Some(u) = n.to_u128() {
+                    visitor.visit_u128(u)
+                } else {
+                    Err(de::Error::custom(format!("integer {} is too large", n)))
+                }
+            }
+            Ratio(r) => match r.to_f64() {
+                Some(f) => visitor.visit_f64(f),
+                None => Err(de::Error::invalid_type(unexpected(self.0), &visitor)),
+            },
+            Float(f) => visitor.visit_f64(*f),
+            Str(s) => visitor.visit_str(s),
+            Sym(s) => visitor.visit_str(&s.name()),
+            Keyword(k) => visitor.visit_str(k),
+            List(l, _) => ValueDeserializer::seq(visitor, l.iter()),
+            Vector(v, _) => ValueDeserializer::seq(visitor, v.iter()),
+            Set(s, _) => ValueDeserializer::seq(visitor, s.iter()),
+            LazySeq(_) => {
+                let items = self
+                    .0
+                    .walk()
+                    .collect::<Result<MalArgs, MalErr>>()
+                    .map_err(de::Error::custom)?;
+                ValueDeserializer::seq(visitor, items.iter())
+            }
+            Hash(hm, _) => visitor.visit_map(MapAccess {
+                iter: hm.iter(),
+                value: None,
+                seg: String::new(),
+            }),
+            Func(..) | MalFunc { .. } | Atom(_) => {
+                Err(de::Error::invalid_type(unexpected(self.0), &visitor))
+            }
+        }
+    }
+
+    fn deserialize_option<V: de::Visitor<'de>>(self, visitor: V) -> Result<V::Value, DeError> {
+        match self.0 {
+            Nil => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: de::Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        match self.0 {
+            Keyword(_) | Str(_) => visitor.visit_enum(EnumAccess {
+                variant: self.0,
+                value: None,
+            }),
+            Hash(hm, _) if hm.len() == 1 => {
+                let (k, v) = hm.iter().next().unwrap();
+                visitor.visit_enum(EnumAccess {
+                    variant: k,
+                    value: Some(v),
+                })
+            }
+            _ => Err(de::Error::invalid_type(
+                unexpected(self.0),
+                &"a keyword or a single entry map",
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+struct SeqAccess<I> {
+    iter: I,
+    idx: usize,
+}
+
+impl<'a, 'de, I: Iterator<Item = &'a MalVal>> de::SeqAccess<'de> for SeqAccess<I> {
+    type Error = DeError;
+
+    fn next_element_seed<T: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, DeError> {
+        match self.iter.next() {
+            Some(v) => {
+                let idx = self.idx;
+                self.idx += 1;
+                seed.deserialize(ValueDeserializer(v))
+                    .map(Some)
+                    .map_err(|e| e.within(&format!("[{}]", idx)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+struct MapAccess<'a, I> {
+    iter: I,
+    value: Option<&'a MalVal>,
+    seg: String,
+}
+
+impl<'a, 'de, I> de::MapAccess<'de> for MapAccess<'a, I>
+where
+    I: Iterator<Item = (&'a MalVal, &'a MalVal)>,
+{
+    type Error = DeError;
+
+    fn next_key_seed<K: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, DeError> {
+        match self.iter.next() {
+            Some((k, v)) => {
+                self.value = Some(v);
+                self.seg = path_segment(k);
+                seed.deserialize(ValueDeserializer(k))
+                    .map(Some)
+                    .map_err(|e| e.within(&self.seg))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, DeError> {
+        match self.value.take() {
+            Some(v) => seed
+                .deserialize(ValueDeserializer(v))
+                .map_err(|e| e.within(&self.seg)),
+            None => Err(de::Error::custom("map value requested before its key")),
+        }
+    }
+}
+
+struct EnumAccess<'a> {
+    variant: &'a MalVal,
+    value: Option<&'a MalVal>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for EnumAccess<'a> {
+    type Error = DeError;
+    type Variant = VariantAccess<'a>;
+
+    fn variant_seed<V: de::DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantAccess<'a>), DeError> {
+        let seg = path_segment(&self.variant.keyword().unwrap_or(Nil));
+        let variant = seed.deserialize(ValueDeserializer(self.variant))?;
+        Ok((
+            variant,
+            VariantAccess {
+                value: self.value,
+                seg,
+            },
+        ))
+    }
+}
+
+struct VariantAccess<'a> {
+    value: Option<&'a MalVal>,
+    seg: String,
+}
+
+impl<'a> VariantAccess<'a> {
+    fn value(&self, what: &'static str) -> Result<&'a MalVal, DeError> {
+        match self.value {
+            Some(v) => Ok(v),
+            None => Err(de::Error::invalid_type(de::Unexpected::UnitVariant, &what)),
+        }
+    }
+}
+
+impl<'a, 'de> de::VariantAccess<'de> for VariantAccess<'a> {
+    type Error = DeError;
+
+    fn unit_variant(self) -> Result<(), DeError> {
+        match self.value {
+            None | Some(Nil) => Ok(()),
+            Some(v) => Err(de::Error::invalid_type(unexpected(v), &"unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: de::DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, DeError> {
+        let v = self.value("newtype variant")?;
+        seed.deserialize(ValueDeserializer(v))
+            .map_err(|e| e.within(&self.seg))
+    }
+
+    fn tuple_variant<V: de::Visitor<'de>>(
+        self,
+        _len: usize,
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        let v = self.value("tuple variant")?;
+        de::Deserializer::deserialize_seq(ValueDeserializer(v), visitor)
+            .map_err(|e| e.within(&self.seg))
+    }
+
+    fn struct_variant<V: de::Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, DeError> {
+        let v = self.value("struct variant")?;
+        de::Deserializer::deserialize_map(ValueDeserializer(v), visitor)
+            .map_err(|e| e.within(&self.seg))
+    }
 }
@@ -0,0 +1,843 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::rc::Rc;
+
+use fnv::FnvHashSet;
+
+use crate::analyze::{analyze, Code, Lambda, Target};
+use crate::env::{check_arity, env_get, env_set, Env};
+use crate::eval::{check_depth, macroexpand, nest, root, top_level};
+use crate::gc;
+use crate::lazy::lazy_seq;
+use crate::symbol::{self, Symbol};
+use crate::types::MalVal::{Atom, Bool, MalFunc, Nil, Sym};
+use crate::types::{error, hash_map, hash_set, Compiled, MalArgs, MalErr, MalRet, MalVal, Span};
+
+// A bytecode backend for stepA, selected with --vm.  Forms are analyzed
+// as for the tree evaluator, so macros are already expanded and locals
+// resolved, then each function is compiled to a flat list of ops and run
+// on a stack machine.  The machine keeps its own call frames: calls
+// between compiled functions don't use the Rust stack, and tail calls
+// reuse the caller's frame.
+//
+// A function's locals (params, let*, catch* and def! bindings) are slots
+// at the bottom of its frame on the stack.  A local that an inner fn* or
+// lazy-seq refers to lives in a box instead (an atom the program never
+// sees), and each closure made gets the box as an upvalue, so it also
+// sees a value bound after it was made, as in a recursive let*.
+
+#[derive(Clone, Copy, Debug)]
+enum Op {
+    Const(u32),
+    Local(u32),
+    LocalBox(u32),
+    Upval(u32),
+    // the operand is the const holding the Sym
+    Global(u32),
+    // the stores leave the value on the stack
+    SetLocal(u32),
+    SetBox(u32),
+    SetUpval(u32),
+    DefGlobal(u32),
+    // put a new box holding nil in a slot, or box the value already there
+    NewBox(u32),
+    BoxLocal(u32),
+    // turn the function on top into a macro
+    Macro,
+    Pop,
+    Jump(u32),
+    JumpIfFalse(u32),
+    // make a closure of the numbered proto
+    Closure(u32),
+    // wrap the closure on top in a lazy seq
+    Lazy,
+    Call(u32),
+    TailCall(u32),
+    Return,
+    Vector(u32),
+    Hash(u32),
+    Set(u32),
+    // handle errors from here on at the given op, until EndTry
+    Try(u32),
+    EndTry,
+    Eval,
+    Macroexpand(u32),
+}
+
+// where a closure gets each upvalue from, in the frame that makes it
+#[derive(Clone, Copy, Debug)]
+enum Capture {
+    Local(u32),
+    Upval(u32),
+}
+
+// call sites and global references, for error messages
+struct Site {
+    pc: usize,
+    name: Symbol,
+    span: Option<Span>,
+}
+
+// a compiled function
+struct Proto {
+    ops: Vec<Op>,
+    consts: Vec<MalVal>,
+    protos: Vec<Rc<Proto>>,
+    captures: Vec<Capture>,
+    // for arity errors
+    name: Symbol,
+    arity: (usize, Option<usize>),
+    nslots: usize,
+    sites: Vec<Site>,
+    // as written, for printing
+    params: Rc<MalVal>,
+    ast: Rc<MalVal>,
+}
+
+pub struct Closure {
+    proto: Rc<Proto>,
+    upvals: Vec<Rc<RefCell<MalVal>>>,
+}
+
+impl Closure {
+    // the boxes this closure holds
+    pub fn upvals(&self) -> &[Rc<RefCell<MalVal>>] {
+        &self.upvals
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Closure({:?}, {:?})", self.proto.ops, self.proto.consts)
+    }
+}
+
+pub fn eval(ast: MalVal, env: Env) -> MalRet {
+    let code = analyze(&top_level(ast, &env, eval)?, &env)?;
+    let closure = script(&code);
+    run(closure, env, vec![])
+}
+
+// calls a function made by fn*, from outside the machine
+pub fn call(closure: &Rc<Closure>, env: &Env, args: MalArgs) -> MalRet {
+    check_arity(closure.proto.name, closure.proto.arity, args.len())?;
+    run(closure.clone(), env.clone(), args)
+}
+
+fn script(code: &Code) -> Rc<Closure> {
+    let mut captured = FnvHashSet::default();
+    find_captured(code, &mut vec![], 0, &mut captured);
+    let mut c = Compiler {
+        captured,
+        scopes: vec![],
+        funcs: vec![],
+    };
+    let proto = c.proto(code, (0, Some(0)), None, Rc::new(list![]), Rc::new(Nil));
+    Rc::new(Closure {
+        proto: Rc::new(proto),
+        upvals: vec![],
+    })
+}
+
+// Frames of locals are told apart by the address of the Code that makes
+// them: the let*, the fn*'s Lambda or the catch* body.
+fn key<T>(x: &T) -> usize {
+    x as *const T as usize
+}
+
+// The locals referred to from a function other than the one they're
+// bound in, as (frame, slot).  scopes holds the frames enclosing the
+// code and the function each belongs to, counting out from the top
+// level; lazy-seq bodies are functions without a frame.
+fn find_captured(
+    code: &Code,
+    scopes: &mut Vec<(usize, usize)>,
+    func: usize,
+    out: &mut FnvHashSet<(usize, usize)>,
+) {
+    match code {
+        Code::Local(depth, slot) => {
+            let (frame, f) = scopes[scopes.len() - 1 - depth];
+            if f != func {
+                out.insert((frame, *slot));
+            }
+        }
+        Code::Def(target, val) | Code::DefMacro(target, val) => {
+            if let (Target::Local(slot), Some(&(frame, f))) = (target, scopes.last()) {
+                if f != func {
+                    out.insert((frame, *slot));
+                }
+            }
+            find_captured(val, scopes, func, out);
+        }
+        Code::Let(_, inits, body) => {
+            scopes.push((key(code), func));
+            for (_, init) in inits {
+                find_captured(init, scopes, func, out);
+            }
+            find_captured(body, scopes, func, out);
+            scopes.pop();
+        }
+        Code::Do(init, last) => {
+            for c in init {
+                find_captured(c, scopes, func, out);
+            }
+            find_captured(last, scopes, func, out);
+        }
+        Code::If(cond, then, otherwise) => {
+            find_captured(cond, scopes, func, out);
+            find_captured(then, scopes, func, out);
+            find_captured(otherwise, scopes, func, out);
+        }
+        Code::Fn(lambda, _, _) => {
+            scopes.push((key(&**lambda), func + 1));
+            find_captured(&lambda.body, scopes, func + 1, out);
+            scopes.pop();
+        }
+        Code::Try(body, handler) => {
+            find_captured(body, scopes, func, out);
+            if let Some((_, handler)) = handler {
+                scopes.push((key(&**handler), func));
+                find_captured(handler, scopes, func, out);
+                scopes.pop();
+            }
+        }
+        Code::LazySeq(body) => find_captured(body, scopes, func + 1, out),
+        Code::Eval(form) => find_captured(form, scopes, func, out),
+        Code::Call(call) => {
+            find_captured(&call.f, scopes, func, out);
+            for a in &call.args {
+                find_captured(a, scopes, func, out);
+            }
+        }
+        Code::Vector(items) | Code::Hash(items) | Code::Set(items) => {
+            for c in items {
+                find_captured(c, scopes, func, out);
+            }
+        }
+        Code::Const(_) | Code::Global(..) | Code::Macroexpand(_) => (),
+    }
+}
+
+struct Scope {
+    key: usize,
+    // the function it belongs to, and its first slot there
+    func: usize,
+    base: usize,
+}
+
+#[derive(Default)]
+struct Func {
+    ops: Vec<Op>,
+    consts: Vec<MalVal>,
+    protos: Vec<Rc<Proto>>,
+    captures: Vec<Capture>,
+    // the (function, slot) each upvalue refers to
+    upvals: Vec<(usize, usize)>,
+    nslots: usize,
+    sites: Vec<Site>,
+}
+
+struct Compiler {
+    captured: FnvHashSet<(usize, usize)>,
+    scopes: Vec<Scope>,
+    funcs: Vec<Func>,
+}
+
+impl Compiler {
+    fn func(&mut self) -> &mut Func {
+        self.funcs.last_mut().unwrap()
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        let f = self.func();
+        f.ops.push(op);
+        f.ops.len() - 1
+    }
+
+    // point the jump at pc to the next op
+    fn patch(&mut self, pc: usize) {
+        let f = self.func();
+        let to = f.ops.len() as u32;
+        f.ops[pc] = match f.ops[pc] {
+            Op::Jump(_) => Op::Jump(to),
+            Op::JumpIfFalse(_) => Op::JumpIfFalse(to),
+            Op::Try(_) => Op::Try(to),
+            op => op,
+        };
+    }
+
+    fn konst(&mut self, v: MalVal) -> u32 {
+        let f = self.func();
+        f.consts.push(v);
+        (f.consts.len() - 1) as u32
+    }
+
+    // the next op is a call or a global reference
+    fn site(&mut self, name: Symbol, span: &Option<Span>) {
+        let f = self.func();
+        f.sites.push(Site {
+            pc: f.ops.len(),
+            name,
+            span: span.clone(),
+        });
+    }
+
+    // The body of a fn* or lazy-seq, or a top-level form.  frame is the
+    // key and size of the frame holding the params and def!s, and how
+    // many params it starts with.
+    fn proto(
+        &mut self,
+        body: &Code,
+        arity: (usize, Option<usize>),
+        frame: Option<(usize, usize, usize)>,
+        params: Rc<MalVal>,
+        ast: Rc<MalVal>,
+    ) -> Proto {
+        self.funcs.push(Func::default());
+        if let Some((key, size, nparams)) = frame {
+            self.enter(key, size);
+            for slot in 0..size {
+                if self.captured.contains(&(key, slot)) {
+                    self.emit(if slot < nparams {
+                        Op::BoxLocal(slot as u32)
+                    } else {
+                        Op::NewBox(slot as u32)
+                    });
+                }
+            }
+        }
+        self.expr(body, true);
+        self.emit(Op::Return);
+        if frame.is_some() {
+            self.scopes.pop();
+        }
+        let f = self.funcs.pop().unwrap();
+        Proto {
+            ops: f.ops,
+            consts: f.consts,
+            protos: f.protos,
+            captures: f.captures,
+            name: symbol::FN,
+            arity,
+            nslots: f.nslots,
+            sites: f.sites,
+            params,
+            ast,
+        }
+    }
+
+    // push a scope of size slots at the end of the current function's
+    fn enter(&mut self, key: usize, size: usize) -> usize {
+        let func = self.funcs.len() - 1;
+        let base = self.func().nslots;
+        self.func().nslots += size;
+        self.scopes.push(Scope { key, func, base });
+        base
+    }
+
+    // boxes for the captured slots of a let* or catch* frame
+    fn new_boxes(&mut self, key: usize, base: usize, size: usize) {
+        for slot in 0..size {
+            if self.captured.contains(&(key, slot)) {
+                self.emit(Op::NewBox((base + slot) as u32));
+            }
+        }
+    }
+
+    // the upvalue of the current function (at level) for a slot of an
+    // enclosing one, added to it and the functions in between as needed
+    fn upval(&mut self, level: usize, target: (usize, usize)) -> u32 {
+        if let Some(i) = self.funcs[level].upvals.iter().position(|&u| u == target) {
+            return i as u32;
+        }
+        let capture = if level - 1 == target.0 {
+            Capture::Local(target.1 as u32)
+        } else {
+            Capture::Upval(self.upval(level - 1, target))
+        };
+        let f = &mut self.funcs[level];
+        f.upvals.push(target);
+        f.captures.push(capture);
+        (f.upvals.len() - 1) as u32
+    }
+
+    // the op to load or store a slot of the frame depth scopes out
+    fn local(&mut self, depth: usize, slot: usize, load: bool) -> Op {
+        let scope = &self.scopes[self.scopes.len() - 1 - depth];
+        let (func, abs) = (scope.func, (scope.base + slot) as u32);
+        let boxed = self.captured.contains(&(scope.key, slot));
+        let level = self.funcs.len() - 1;
+        match (func == level, boxed, load) {
+            (true, false, true) => Op::Local(abs),
+            (true, false, false) => Op::SetLocal(abs),
+            (true, true, true) => Op::LocalBox(abs),
+            (true, true, false) => Op::SetBox(abs),
+            (false, _, true) => Op::Upval(self.upval(level, (func, abs as usize))),
+            (false, _, false) => Op::SetUpval(self.upval(level, (func, abs as usize))),
+        }
+    }
+
+    fn store(&mut self, target: &Target) {
+        let op = match target {
+            Target::Local(slot) => self.local(0, *slot, false),
+            Target::Global(name) => Op::DefGlobal(self.konst(name.clone())),
+        };
+        self.emit(op);
+    }
+
+    // leaves the value of code on the stack
+    fn expr(&mut self, code: &Code, tail: bool) {
+        match code {
+            Code::Const(v) => {
+                let i = self.konst(v.clone());
+                self.emit(Op::Const(i));
+            }
+            Code::Local(depth, slot) => {
+                let op = self.local(*depth, *slot, true);
+                self.emit(op);
+            }
+            Code::Global(_, name, span) => {
+                if let Sym(s) = name {
+                    self.site(*s, span);
+                }
+                let i = self.konst(name.clone());
+                self.emit(Op::Global(i));
+            }
+            Code::Def(target, val) => {
+                self.expr(val, false);
+                self.store(target);
+            }
+            Code::DefMacro(target, val) => {
+                self.expr(val, false);
+                self.emit(Op::Macro);
+                self.store(target);
+            }
+            Code::Let(size, inits, body) => {
+                let base = self.enter(key(code), *size);
+                self.new_boxes(key(code), base, *size);
+                for (slot, init) in inits {
+                    self.expr(init, false);
+                    let op = self.local(0, *slot, false);
+                    self.emit(op);
+                    self.emit(Op::Pop);
+                }
+                self.expr(body, tail);
+                self.scopes.pop();
+            }
+            Code::Do(init, last) => {
+                for c in init {
+                    self.expr(c, false);
+                    self.emit(Op::Pop);
+                }
+                self.expr(last, tail);
+            }
+            Code::If(cond, then, otherwise) => {
+                self.expr(cond, false);
+                let to_else = self.emit(Op::JumpIfFalse(0));
+                self.expr(then, tail);
+                let to_end = self.emit(Op::Jump(0));
+                self.patch(to_else);
+                self.expr(otherwise, tail);
+                self.patch(to_end);
+            }
+            Code::Fn(lambda, params, body) => {
+                let proto = self.lambda(lambda, params.clone(), body.clone());
+                self.emit(Op::Closure(proto));
+            }
+            Code::Try(body, None) => self.expr(body, tail),
+            Code::Try(body, Some((size, handler))) => {
+                // the body can't make a tail call, as the handler has to
+                // stay in place until it returns
+                let to_handler = self.emit(Op::Try(0));
+                self.expr(body, false);
+                self.emit(Op::EndTry);
+                let to_end = self.emit(Op::Jump(0));
+                self.patch(to_handler);
+                let base = self.enter(key(&**handler), *size);
+                self.new_boxes(key(&**handler), base, *size);
+                let op = self.local(0, 0, false);
+                self.emit(op);
+                self.emit(Op::Pop);
+                self.expr(handler, tail);
+                self.scopes.pop();
+                self.patch(to_end);
+            }
+            Code::LazySeq(body) => {
+                let proto = self.proto(body, (0, Some(0)), None, Rc::new(list![]), Rc::new(Nil));
+                let f = self.func();
+                f.protos.push(Rc::new(proto));
+                let i = (f.protos.len() - 1) as u32;
+                self.emit(Op::Closure(i));
+                self.emit(Op::Lazy);
+            }
+            Code::Eval(form) => {
+                self.expr(form, false);
+                self.emit(Op::Eval);
+            }
+            Code::Macroexpand(form) => {
+                let i = self.konst(form.clone());
+                self.emit(Op::Macroexpand(i));
+            }
+            Code::Call(call) => {
+                self.expr(&call.f, false);
+                for a in &call.args {
+                    self.expr(a, false);
+                }
+                self.site(call.name, &call.span);
+                let n = call.args.len() as u32;
+                self.emit(if tail { Op::TailCall(n) } else { Op::Call(n) });
+            }
+            Code::Vector(items) | Code::Hash(items) | Code::Set(items) => {
+                for c in items {
+                    self.expr(c, false);
+                }
+                let n = items.len() as u32;
+                self.emit(match code {
+                    Code::Vector(_) => Op::Vector(n),
+                    Code::Hash(_) => Op::Hash(n),
+                    _ => Op::Set(n),
+                });
+            }
+        }
+    }
+
+    fn lambda(&mut self, lambda: &Rc<Lambda>, params: Rc<MalVal>, body: Rc<MalVal>) -> u32 {
+        let (min, max) = lambda.arity;
+        let nparams = if max.is_none() { min + 1 } else { min };
+        let frame = Some((key(&**lambda), lambda.frame_size, nparams));
+        let mut proto = self.proto(&lambda.body, lambda.arity, frame, params, body);
+        proto.name = lambda.name;
+        let f = self.func();
+        f.protos.push(Rc::new(proto));
+        (f.protos.len() - 1) as u32
+    }
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    env: Env,
+    // the next op, and where the frame's slots start on the stack (the
+    // function called is just below)
+    pc: usize,
+    base: usize,
+}
+
+struct Handler {
+    frames: usize,
+    sp: usize,
+    pc: usize,
+}
+
+struct Machine {
+    stack: Vec<MalVal>,
+    frames: Vec<Frame>,
+    handlers: Vec<Handler>,
+}
+
+fn run(closure: Rc<Closure>, env: Env, args: MalArgs) -> MalRet {
+    let _guard = nest()?;
+    let mut m = Machine {
+        stack: vec![Nil],
+        frames: vec![],
+        handlers: vec![],
+    };
+    m.stack.extend(args);
+    m.enter(closure, env, 1);
+    loop {
+        match m.exec() {
+            Ok(v) => return Ok(v),
+            Err(e) => m.catch(e)?,
+        }
+    }
+}
+
+// a box is registered with the collector as an upvalue, so that (gc)
+// only counts the atoms the program made
+fn new_box(v: &MalVal) -> MalVal {
+    let b = Rc::new(RefCell::new(v.clone()));
+    gc::track_upval(&b);
+    Atom(b)
+}
+
+fn unbox(v: &MalVal) -> MalVal {
+    match v {
+        Atom(b) => b.borrow().clone(),
+        _ => v.clone(),
+    }
+}
+
+impl Machine {
+    // start running closure, with its args on the stack from base; the
+    // arity and depth have been checked
+    fn enter(&mut self, closure: Rc<Closure>, env: Env, base: usize) {
+        let proto = &closure.proto;
+        if let (min, None) = proto.arity {
+            let rest = self.stack.split_off(base + min);
+            self.stack.push(list!(rest));
+        }
+        self.stack.resize(base + proto.nslots, Nil);
+        self.frames.push(Frame {
+            closure,
+            env,
+            pc: 0,
+            base,
+        });
+    }
+
+    // unwinds to the innermost handler, or fails with e if there's none
+    fn catch(&mut self, e: MalErr) -> Result<(), MalErr> {
+        let e = match self.frames.last() {
+            Some(f) => {
+                let sites = &f.closure.proto.sites;
+                match sites.binary_search_by_key(&(f.pc - 1), |s| s.pc) {
+                    Ok(i) => e.at(sites[i].span.clone()),
+                    Err(_) => e,
+                }
+            }
+            None => e,
+        };
+        let h = match self.handlers.pop() {
+            Some(h) => h,
+            None => return Err(e),
+        };
+        self.frames.truncate(h.frames);
+        self.stack.truncate(h.sp);
+        self.stack.push(e.value());
+        self.frames.last_mut().unwrap().pc = h.pc;
+        Ok(())
+    }
+
+    fn site_name(&self, pc: usize) -> Symbol {
+        let sites = &self.frames.last().unwrap().closure.proto.sites;
+        match sites.binary_search_by_key(&pc, |s| s.pc) {
+            Ok(i) => sites[i].name,
+            Err(_) => symbol::FN,
+        }
+    }
+
+    // runs until the first frame returns; on an error the current
+    // frame's pc is left just past the op that failed
+    fn exec(&mut self) -> MalRet {
+        let frame = self.frames.last().unwrap();
+        let mut closure = frame.closure.clone();
+        let mut env = frame.env.clone();
+        let mut pc = frame.pc;
+        let mut base = frame.base;
+
+        macro_rules! fail {
+            ($e:expr) => {{
+                self.frames.last_mut().unwrap().pc = pc;
+                return Err($e);
+            }};
+        }
+        macro_rules! attempt {
+            ($e:expr) => {
+                match $e {
+                    Ok(v) => v,
+                    Err(e) => fail!(e),
+                }
+            };
+        }
+        macro_rules! reload {
+            () => {{
+                let frame = self.frames.last().unwrap();
+                closure = frame.closure.clone();
+                env = frame.env.clone();
+                pc = frame.pc;
+                base = frame.base;
+            }};
+        }
+
+        loop {
+            let proto = &closure.proto;
+            let op = proto.ops[pc];
+            pc += 1;
+            match op {
+                Op::Const(i) => self.stack.push(proto.consts[i as usize].clone()),
+                Op::Local(i) => {
+                    let v = self.stack[base + i as usize].clone();
+                    self.stack.push(v);
+                }
+                Op::LocalBox(i) => {
+                    let v = unbox(&self.stack[base + i as usize]);
+                    self.stack.push(v);
+                }
+                Op::Upval(i) => {
+                    let v = closure.upvals[i as usize].borrow().clone();
+                    self.stack.push(v);
+                }
+                Op::Global(i) => {
+                    let v = attempt!(env_get(&env, &proto.consts[i as usize]));
+                    self.stack.push(v);
+                }
+                Op::SetLocal(i) => {
+                    let v = self.stack.last().unwrap().clone();
+                    self.stack[base + i as usize] = v;
+                }
+                Op::SetBox(i) => {
+                    let v = self.stack.last().unwrap().clone();
+                    if let Atom(ref b) = self.stack[base + i as usize] {
+                        *b.borrow_mut() = v;
+                    }
+                }
+                Op::SetUpval(i) => {
+                    let v = self.stack.last().unwrap().clone();
+                    *closure.upvals[i as usize].borrow_mut() = v;
+                }
+                Op::DefGlobal(i) => {
+                    let v = self.stack.last().unwrap().clone();
+                    attempt!(env_set(&env, proto.consts[i as usize].clone(), v));
+                }
+                Op::NewBox(i) => self.stack[base + i as usize] = new_box(&Nil),
+                Op::BoxLocal(i) => {
+                    let b = new_box(&self.stack[base + i as usize]);
+                    self.stack[base + i as usize] = b;
+                }
+                Op::Macro => match self.stack.pop().unwrap() {
+                    MalFunc {
+                        eval,
+                        ast,
+                        env,
+                        params,
+                        code,
+                        ..
+                    } => self.stack.push(MalFunc {
+                        eval,
+                        ast,
+                        env,
+                        params,
+                        code,
+                        is_macro: true,
+                        meta: Rc::new(Nil),
+                    }),
+                    _ => fail!(error("set_macro on non-function").unwrap_err()),
+                },
+                Op::Pop => {
+                    self.stack.pop();
+                }
+                Op::Jump(to) => pc = to as usize,
+                Op::JumpIfFalse(to) => {
+                    if let Bool(false) | Nil = self.stack.pop().unwrap() {
+                        pc = to as usize;
+                    }
+                }
+                Op::Closure(i) => {
+                    let p = proto.protos[i as usize].clone();
+                    let upvals = p
+                        .captures
+                        .iter()
+                        .map(|c| match *c {
+                            // captured slots were boxed when their frame began
+                            Capture::Local(slot) => match self.stack[base + slot as usize] {
+                                Atom(ref b) => b.clone(),
+                                _ => unreachable!("captured local not boxed"),
+                            },
+                            Capture::Upval(u) => closure.upvals[u as usize].clone(),
+                        })
+                        .collect();
+                    self.stack.push(MalFunc {
+                        eval,
+                        ast: p.ast.clone(),
+                        env: env.clone(),
+                        params: p.params.clone(),
+                        code: Some(Compiled::Bytecode(Rc::new(Closure { proto: p, upvals }))),
+                        is_macro: false,
+                        meta: Rc::new(Nil),
+                    });
+                }
+                Op::Lazy => {
+                    let f = self.stack.pop().unwrap();
+                    self.stack.push(lazy_seq(move || f.apply(vec![])));
+                }
+                Op::Call(n) | Op::TailCall(n) => {
+                    let fpos = self.stack.len() - n as usize - 1;
+                    let callee = match self.stack[fpos] {
+                        MalFunc {
+                            code: Some(Compiled::Bytecode(ref c)),
+                            ref env,
+                            ..
+                        } => Some((c.clone(), env.clone())),
+                        _ => None,
+                    };
+                    match callee {
+                        Some((c, cenv)) => {
+                            let (min, max) = c.proto.arity;
+                            let n = n as usize;
+                            if n < min || max.is_some_and(|max| n > max) {
+                                let name = self.site_name(pc - 1);
+                                attempt!(check_arity(name, c.proto.arity, n));
+                            }
+                            let start = if let Op::TailCall(_) = op {
+                                // slide the callee and args down over this frame
+                                self.stack.drain(base - 1..fpos);
+                                self.frames.pop();
+                                base
+                            } else {
+                                attempt!(check_depth(self.frames.len()));
+                                self.frames.last_mut().unwrap().pc = pc;
+                                fpos + 1
+                            };
+                            self.enter(c, cenv, start);
+                            reload!();
+                        }
+                        None => {
+                            let args = self.stack.split_off(fpos + 1);
+                            let f = self.stack.pop().unwrap();
+                            self.stack.push(attempt!(f.apply(args)));
+                        }
+                    }
+                }
+                Op::Return => {
+                    let v = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    self.stack.truncate(frame.base - 1);
+                    if self.frames.is_empty() {
+                        return Ok(v);
+                    }
+                    self.stack.push(v);
+                    reload!();
+                }
+                Op::Vector(n) => {
+                    let items = self.stack.split_off(self.stack.len() - n as usize);
+                    self.stack.push(vector!(items));
+                }
+                Op::Hash(n) => {
+                    let items = self.stack.split_off(self.stack.len() - n as usize);
+                    self.stack.push(attempt!(hash_map(items)));
+                }
+                Op::Set(n) => {
+                    let items = self.stack.split_off(self.stack.len() - n as usize);
+                    self.stack.push(hash_set(items));
+                }
+                Op::Try(to) => self.handlers.push(Handler {
+                    frames: self.frames.len(),
+                    sp: self.stack.len(),
+                    pc: to as usize,
+                }),
+                Op::EndTry => {
+                    self.handlers.pop();
+                }
+                Op::Eval => {
+                    // run the form as a function of no args in the top env
+                    attempt!(check_depth(self.frames.len()));
+                    let form = self.stack.pop().unwrap();
+                    let top = root(&env);
+                    let form = attempt!(top_level(form, &top, eval));
+                    let code = attempt!(analyze(&form, &top));
+                    let c = script(&code);
+                    self.stack.push(Nil);
+                    self.frames.last_mut().unwrap().pc = pc;
+                    let start = self.stack.len();
+                    self.enter(c, top, start);
+                    reload!();
+                }
+                Op::Macroexpand(i) => {
+                    let form = proto.consts[i as usize].clone();
+                    let v = attempt!(macroexpand(form, &root(&env)).1);
+                    self.stack.push(v);
+                }
+            }
+        }
+    }
+}